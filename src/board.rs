@@ -0,0 +1,494 @@
+use crate::escc::ESCC;
+use crate::mcs51::cpu::{Address, CpuError, CPU};
+use crate::mcs51::memory::{Memory, OpenBus, RAM, ROM};
+use crate::mcs51::soc::p80c550::{self, ChipVariant, Peripherals};
+use crate::mcs51::symbols::SymbolTable;
+use crate::sdcard::SdCard;
+use crate::spi::{NullDevice, SPI};
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+// 11.0592 MHz crystal
+pub const CLOCK_HZ: u64 = 11059200;
+
+// size of the code space
+pub const CODE_SIZE: usize = 0x10000;
+
+// external ram from the bottom of the external data space
+pub const XRAM_SIZE: usize = 0x8000;
+
+// the external ram, or the open bus movx sees in its window on a board built without it
+enum Xram {
+    Present(RAM),
+    Absent(OpenBus),
+}
+
+// devices on the external data bus of the EVN board
+pub struct ExternalBus {
+    ram: Xram,
+    escc: ESCC,
+    spi: SPI,
+}
+
+impl Default for ExternalBus {
+    fn default() -> ExternalBus {
+        ExternalBus {
+            ram: Xram::Present(RAM::create_with_size(XRAM_SIZE)),
+            escc: ESCC::new(),
+            spi: SPI::new([
+                Rc::new(NullDevice::new()),
+                Rc::new(NullDevice::new()),
+                Rc::new(SdCard::new()),
+            ]),
+        }
+    }
+}
+
+impl ExternalBus {
+    pub fn new() -> ExternalBus {
+        ExternalBus::default()
+    }
+
+    // leave the ram unpopulated, accesses to its window go to the open bus instead
+    pub fn remove_ram(&mut self, open_bus: OpenBus) {
+        self.ram = Xram::Absent(open_bus);
+    }
+
+    // the open bus in place of the ram, if it was removed
+    pub fn open_bus(&self) -> Option<&OpenBus> {
+        match &self.ram {
+            Xram::Present(_) => None,
+            Xram::Absent(open_bus) => Some(open_bus),
+        }
+    }
+
+    fn ram_window(&mut self) -> &mut dyn Memory {
+        match &mut self.ram {
+            Xram::Present(ram) => ram,
+            Xram::Absent(open_bus) => open_bus,
+        }
+    }
+}
+
+impl Memory for ExternalBus {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+        match address {
+            Address::ExternalData(a) => {
+                if (a as usize) < XRAM_SIZE {
+                    self.ram_window().read_memory(address)
+                } else {
+                    match a {
+                        0x8400..=0x8401 => self.spi.read_memory(address),
+                        0x9400..=0x9403 => self.escc.read_memory(address),
+                        _ => Err(CpuError::Fault("unused address (read)")),
+                    }
+                }
+            }
+            _ => Err(CpuError::Fault("unsupported address space")),
+        }
+    }
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        match address {
+            Address::ExternalData(a) => {
+                if (a as usize) < XRAM_SIZE {
+                    self.ram_window().write_memory(address, data)
+                } else {
+                    match a {
+                        0x8400..=0x8401 => self.spi.write_memory(address, data),
+                        0x9400..=0x9403 => self.escc.write_memory(address, data),
+                        _ => Err(CpuError::Fault("unused address (write)")),
+                    }
+                }
+            }
+            _ => Err(CpuError::Fault("unsupported address space")),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.escc.tick();
+        self.spi.tick();
+    }
+}
+
+// the P80C550 EVN board: 64K of code space, 32K of xram, an SPI controller (sd card on the
+// third select) and an ESCC. the board starts with erased code memory, load firmware before
+// stepping.
+pub struct Board {
+    cpu: CPU<Peripherals<ROM, ExternalBus>>,
+}
+
+impl Default for Board {
+    fn default() -> Board {
+        Board {
+            cpu: p80c550::create(
+                Rc::new(ROM::blank(CODE_SIZE)),
+                Rc::new(ExternalBus::new()),
+                ChipVariant::P80C550,
+            ),
+        }
+    }
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board::default()
+    }
+
+    // load firmware as sdcc intel hex (.ihx / .hex), ELF (with the elf feature) or a raw
+    // binary, along with the sdcc linker map next to it (if any) for symbolic tracing
+    pub fn load_firmware(
+        &mut self,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let mut rom = match path.extension().and_then(|e| e.to_str()) {
+            Some("ihx") | Some("hex") => ROM::load_from_ihx(path)?,
+            #[cfg(feature = "elf")]
+            Some("elf") => {
+                self.cpu.set_symbols(SymbolTable::load_elf(path)?);
+                ROM::load_from_elf(path)?
+            }
+            #[cfg(not(feature = "elf"))]
+            Some("elf") => return Err("ELF images need the elf feature".into()),
+            _ => ROM::load_from_binary(path)?,
+        };
+        rom.resize(CODE_SIZE);
+        *self.cpu.memory_mut().rom_mut() = rom;
+
+        let map_path = path.with_extension("map");
+        if map_path.exists() {
+            self.cpu.set_symbols(SymbolTable::load_sdcc_map(&map_path)?);
+        }
+        Ok(())
+    }
+
+    // record the serial traffic to a file, one line per byte: the machine cycle, rx or tx and
+    // the byte in hex. received bytes are stamped when the host queued them. a failed write
+    // stops the recording and is returned by finish.
+    pub fn record_uart(&mut self, path: &Path) -> io::Result<()> {
+        let transcript = BufWriter::new(File::create(path)?);
+        self.cpu
+            .memory_mut()
+            .serial_mut()
+            .set_transcript(Some(Box::new(transcript)));
+        Ok(())
+    }
+
+    // feed the received bytes of a recorded transcript back in at the cycles they arrived,
+    // the transmitted bytes are left for comparing against
+    pub fn replay_uart(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let serial = self.cpu.memory_mut().serial_mut();
+        for line in fs::read_to_string(path)?.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                [cycle, "rx", data] => {
+                    serial.receive_at(cycle.parse()?, u8::from_str_radix(data, 16)?)
+                }
+                [_, "tx", _] => {}
+                _ => return Err("malformed uart transcript line".into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU<Peripherals<ROM, ExternalBus>> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<Peripherals<ROM, ExternalBus>> {
+        &mut self.cpu
+    }
+
+    // build the board without external ram, movx to its window then reads the open bus value
+    // and writes are dropped
+    pub fn remove_xram(&mut self, open_bus: OpenBus) {
+        self.cpu.memory_mut().xram_mut().remove_ram(open_bus);
+    }
+
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.cpu.step()
+    }
+
+    // call when done running, flushes the uart transcript and instruction trace and delivers
+    // a serial frame still in flight
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.cpu.finish()
+    }
+
+    // copy bytes into external ram without going through the cpu. only the ram is touched,
+    // bytes landing outside of it (e.g. on a device register) or without ram are dropped.
+    pub fn load_xram(&mut self, base: u16, bytes: &[u8]) {
+        let ram = match &mut self.cpu.memory_mut().xram_mut().ram {
+            Xram::Present(ram) => ram,
+            Xram::Absent(_) => return,
+        };
+        for (offset, data) in bytes.iter().enumerate() {
+            let address = base.wrapping_add(offset as u16);
+            if (address as usize) < XRAM_SIZE {
+                // in range, can't fail
+                let _ = ram.write_memory(Address::ExternalData(address), *data);
+            }
+        }
+    }
+
+    // copy bytes out of external ram, without ram its window reads as the open bus value (as
+    // movx sees it, but not recorded as an access). addresses outside the window read as 0xff,
+    // device registers aren't read since reading them has side effects.
+    pub fn dump_xram(&mut self, base: u16, len: u16) -> Vec<u8> {
+        let ram = &mut self.cpu.memory_mut().xram_mut().ram;
+        (0..len)
+            .map(|offset| {
+                let address = base.wrapping_add(offset);
+                if (address as usize) >= XRAM_SIZE {
+                    return 0xff;
+                }
+                match ram {
+                    Xram::Present(ram) => ram
+                        .read_memory(Address::ExternalData(address))
+                        .unwrap_or(0xff),
+                    Xram::Absent(open_bus) => open_bus.value(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a board with program at 0x0000 of its code memory
+    fn board_with_program(program: &[u8]) -> Board {
+        let mut board = Board::new();
+        let rom = board.cpu_mut().memory_mut().rom_mut();
+        rom.set_writable(true);
+        for (address, byte) in program.iter().enumerate() {
+            rom.write_memory(Address::Code(address as u16), *byte)
+                .unwrap();
+        }
+        board
+    }
+
+    #[test]
+    fn movx_without_xram_sees_open_bus() {
+        // MOV DPTR, #0x0100; MOV A, #0x12; MOVX @DPTR, A; CLR A; MOVX A, @DPTR
+        let mut board = board_with_program(&[0x90, 0x01, 0x00, 0x74, 0x12, 0xF0, 0xE4, 0xE0]);
+        board.remove_xram(OpenBus::new(0x5A, 16));
+        for _ in 0..5 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu().state().accumulator, 0x5A);
+        assert_eq!(
+            board
+                .cpu_mut()
+                .memory_mut()
+                .xram_mut()
+                .open_bus()
+                .unwrap()
+                .accesses(),
+            &[(0x0100, Some(0x12)), (0x0100, None)]
+        );
+        assert_eq!(board.dump_xram(0x0100, 1), vec![0x5A]);
+    }
+
+    // the 128 bytes of internal ram
+    fn internal_ram(board: &mut Board) -> Vec<u8> {
+        let memory = board.cpu_mut().memory_mut();
+        (0..0x80u8)
+            .map(|address| memory.read_memory(Address::InternalData(address)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn identical_boards_stay_in_lockstep() {
+        let mut program = vec![0; 0x43];
+        // LJMP 0x0030
+        program[0x00..0x03].copy_from_slice(&[0x02, 0x00, 0x30]);
+        // timer 0: INC 0x30; RETI
+        program[0x0B..0x0E].copy_from_slice(&[0x05, 0x30, 0x32]);
+        // MOV TMOD, #0x02; MOV TH0, #0xF0; MOV IE, #0x82; SETB TR0
+        // loop: MOV DPTR, #0x0200; MOVX A, @DPTR; INC A; MOVX @DPTR, A; SJMP loop
+        program[0x30..0x43].copy_from_slice(&[
+            0x75, 0x89, 0x02, 0x75, 0x8C, 0xF0, 0x75, 0xA8, 0x82, 0xD2, 0x8C, 0x90, 0x02, 0x00,
+            0xE0, 0x04, 0xF0, 0x80, 0xF8,
+        ]);
+        let mut first = board_with_program(&program);
+        let mut second = board_with_program(&program);
+        for step in 0..5000 {
+            first.step().unwrap();
+            second.step().unwrap();
+            assert_eq!(first.cpu().state(), second.cpu().state(), "step {}", step);
+        }
+        let iram = internal_ram(&mut first);
+        assert_eq!(iram, internal_ram(&mut second));
+        assert_eq!(first.dump_xram(0x0200, 1), second.dump_xram(0x0200, 1));
+        // both the interrupt and the main loop made progress
+        assert_ne!(iram[0x30], 0);
+        assert_ne!(first.dump_xram(0x0200, 1), vec![0]);
+    }
+
+    #[test]
+    fn default_board_runs_a_hex_image() {
+        // MOV A, #0x5A; SJMP $
+        let path = std::env::temp_dir().join("p80c550_board_default.ihx");
+        std::fs::write(&path, ":04000000745A80FEB0\n:00000001FF\n").unwrap();
+        let mut board = Board::default();
+        let loaded = board.load_firmware(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        for _ in 0..3 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu().state().accumulator, 0x5A);
+        assert_eq!(board.cpu().state().program_counter, 0x0002);
+    }
+
+    #[test]
+    fn xram_seeded_copied_and_dumped() {
+        // MOV R7, #8; MOV R0, #0x00
+        // loop: MOV P2, #0x10; MOVX A, @R0; MOV P2, #0x20; MOVX @R0, A; INC R0; DJNZ R7, loop
+        // SJMP $
+        let mut board = board_with_program(&[
+            0x7F, 0x08, 0x78, 0x00, 0x75, 0xA0, 0x10, 0xE2, 0x75, 0xA0, 0x20, 0xF2, 0x08, 0xDF,
+            0xF5, 0x80, 0xFE,
+        ]);
+        let bytes = [0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x23, 0x45, 0x67];
+        board.load_xram(0x1000, &bytes);
+        for _ in 0..60 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu().state().program_counter, 0x000F);
+        assert_eq!(board.dump_xram(0x2000, 8), bytes);
+        // nothing past the copy, and the source is intact
+        assert_eq!(board.dump_xram(0x2008, 1), vec![0x00]);
+        assert_eq!(board.dump_xram(0x1000, 8), bytes);
+        // outside the ram reads as 0xff
+        assert_eq!(board.dump_xram(0x7FFF, 2), vec![0x00, 0xff]);
+    }
+
+    // echo every received byte plus one:
+    // MOV SCON, #0x90
+    // loop: JNB RI, loop; MOV A, SBUF; CLR RI; INC A; MOV SBUF, A
+    // wait: JNB TI, wait; CLR TI; SJMP loop
+    const ECHO_PROGRAM: [u8; 20] = [
+        0x75, 0x98, 0x90, 0x30, 0x98, 0xFD, 0xE5, 0x99, 0xC2, 0x98, 0x04, 0xF5, 0x99, 0x30, 0x99,
+        0xFD, 0xC2, 0x99, 0x80, 0xEF,
+    ];
+
+    fn transmitted(board: &mut Board) -> Vec<u8> {
+        let serial = board.cpu_mut().memory_mut().serial_mut();
+        std::iter::from_fn(|| serial.transmitted()).collect()
+    }
+
+    #[test]
+    fn uart_transcript_replays_to_same_output() {
+        let recording = std::env::temp_dir().join("p80c550_uart_recording.txt");
+        let replayed = std::env::temp_dir().join("p80c550_uart_replayed.txt");
+
+        let mut board = board_with_program(&ECHO_PROGRAM);
+        board.record_uart(&recording).unwrap();
+        for &request in [0x41, 0x61].iter() {
+            for _ in 0..100 {
+                board.step().unwrap();
+            }
+            board.cpu_mut().memory_mut().serial_mut().receive(request);
+        }
+        for _ in 0..300 {
+            board.step().unwrap();
+        }
+        board.finish().unwrap();
+        assert_eq!(transmitted(&mut board), [0x42, 0x62]);
+
+        let mut board = board_with_program(&ECHO_PROGRAM);
+        board.replay_uart(&recording).unwrap();
+        board.record_uart(&replayed).unwrap();
+        for _ in 0..500 {
+            board.step().unwrap();
+        }
+        board.finish().unwrap();
+        assert_eq!(transmitted(&mut board), [0x42, 0x62]);
+
+        let first = std::fs::read_to_string(&recording).unwrap();
+        let second = std::fs::read_to_string(&replayed).unwrap();
+        std::fs::remove_file(&recording).unwrap();
+        std::fs::remove_file(&replayed).unwrap();
+        assert_eq!(first.lines().count(), 4);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn finish_delivers_byte_in_flight() {
+        // MOV SCON, #0x80; MOV SBUF, #0x55; SJMP $
+        let mut board = board_with_program(&[0x75, 0x98, 0x80, 0x75, 0x99, 0x55, 0x80, 0xFE]);
+        let path = std::env::temp_dir().join("p80c550_uart_finish.txt");
+        board.record_uart(&path).unwrap();
+        for _ in 0..3 {
+            board.step().unwrap();
+        }
+        assert_eq!(transmitted(&mut board), []);
+        board.finish().unwrap();
+        assert_eq!(transmitted(&mut board), [0x55]);
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(transcript.ends_with(" tx 55\n"), "{}", transcript);
+    }
+
+    // fails every write
+    struct FullWriter;
+
+    impl io::Write for FullWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_uart_recording_is_reported_by_finish() {
+        let mut board = board_with_program(&ECHO_PROGRAM);
+        board
+            .cpu_mut()
+            .memory_mut()
+            .serial_mut()
+            .set_transcript(Some(Box::new(FullWriter)));
+        board.cpu_mut().memory_mut().serial_mut().receive(0x41);
+        for _ in 0..300 {
+            board.step().unwrap();
+        }
+        // the firmware still sees the byte, only the recording stopped
+        assert_eq!(transmitted(&mut board), [0x42]);
+        let error = board.finish().unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    #[test]
+    fn bulk_xram_load_read_by_guest() {
+        // MOV DPTR, #0x1000; MOV R2, #0x00; MOV R6, #4; MOV R7, #0
+        // loop: MOVX A, @DPTR; ADD A, R2; MOV R2, A; INC DPTR; DJNZ R7, loop; DJNZ R6, loop
+        // SJMP $
+        let mut board = board_with_program(&[
+            0x90, 0x10, 0x00, 0x7A, 0x00, 0x7E, 0x04, 0x7F, 0x00, 0xE0, 0x2A, 0xFA, 0xA3, 0xDF,
+            0xFA, 0xDE, 0xF8, 0x80, 0xFE,
+        ]);
+        let buffer: Vec<u8> = (0..1024u32).map(|i| (i * 7 + 3) as u8).collect();
+        board.load_xram(0x1000, &buffer);
+        for _ in 0..6000 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu().state().program_counter, 0x0011);
+        // the guest's 8-bit sum of the buffer, in R2
+        let sum = buffer.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let r2 = board
+            .cpu_mut()
+            .memory_mut()
+            .read_memory(Address::InternalData(0x02));
+        assert_eq!(r2, Ok(sum));
+        assert_eq!(board.dump_xram(0x1000, 1024), buffer);
+    }
+}
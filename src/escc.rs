@@ -1,4 +1,4 @@
-use crate::mcs51::cpu::Address;
+use crate::mcs51::cpu::{Address, CpuError};
 use crate::mcs51::memory::Memory;
 
 pub struct ESCC {
@@ -16,7 +16,7 @@ impl ESCC {
 }
 
 impl Memory for ESCC {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         match address {
             Address::ExternalData(a) => {
                 let address = a & 3;
@@ -48,13 +48,13 @@ impl Memory for ESCC {
                         println!("am85c30.channel.a.data");
                         Ok(0x00)
                     }
-                    _ => Err("unused address (read)"),
+                    _ => Err(CpuError::Fault("unused address (read)")),
                 }
             }
-            _ => Err("unsupported address space"),
+            _ => Err(CpuError::Fault("unsupported address space")),
         }
     }
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str> {
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
         match address {
             Address::ExternalData(a) => {
                 let address = a & 3;
@@ -113,10 +113,10 @@ impl Memory for ESCC {
                         println!("am85c30.channel.a.data = {:x}", data);
                         Ok(())
                     }
-                    _ => Err("unused address (write)"),
+                    _ => Err(CpuError::Fault("unused address (write)")),
                 }
             }
-            _ => Err("unsupported address space"),
+            _ => Err(CpuError::Fault("unsupported address space")),
         }
     }
 
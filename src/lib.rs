@@ -0,0 +1,5 @@
+pub mod board;
+pub mod escc;
+pub mod mcs51;
+pub mod sdcard;
+pub mod spi;
@@ -0,0 +1,74 @@
+use crate::mcs51::cpu::Instruction;
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    // falling through to the next instruction (including returning from a call)
+    Sequential,
+    // a jump, or the taken side of a conditional branch
+    Branch,
+    Call,
+}
+
+// code reachable from an entry point, found by following the instructions without running them
+#[derive(Clone, Debug, Default)]
+pub struct ControlFlowGraph {
+    // reachable instructions with their length in bytes
+    pub instructions: BTreeMap<u16, (Instruction, u16)>,
+    // (from, to, kind), from is the address of the instruction transferring control
+    pub edges: Vec<(u16, u16, EdgeKind)>,
+    // JMP @A+DPTR sites, their targets depend on run time state
+    pub computed_jumps: Vec<u16>,
+    // reachable addresses which don't hold a valid instruction
+    pub undecodable: Vec<u16>,
+}
+
+impl ControlFlowGraph {
+    // where control can go after an instruction, calls are assumed to return
+    pub fn successors(instruction: Instruction, next_address: u16) -> Vec<(u16, EdgeKind)> {
+        let relative = |offset: i8| next_address.wrapping_add(offset as i16 as u16);
+        match instruction {
+            Instruction::AJMP(address) => {
+                vec![((next_address & 0xF800) | address, EdgeKind::Branch)]
+            }
+            Instruction::LJMP(address) => vec![(address, EdgeKind::Branch)],
+            Instruction::SJMP(offset) => vec![(relative(offset), EdgeKind::Branch)],
+            Instruction::ACALL(address) => vec![
+                ((next_address & 0xF800) | address, EdgeKind::Call),
+                (next_address, EdgeKind::Sequential),
+            ],
+            Instruction::LCALL(address) => vec![
+                (address, EdgeKind::Call),
+                (next_address, EdgeKind::Sequential),
+            ],
+            Instruction::CJNE(_, _, offset)
+            | Instruction::DJNZ(_, offset)
+            | Instruction::JB(_, offset)
+            | Instruction::JBC(_, offset)
+            | Instruction::JNB(_, offset)
+            | Instruction::JC(offset)
+            | Instruction::JNC(offset)
+            | Instruction::JZ(offset)
+            | Instruction::JNZ(offset) => vec![
+                (relative(offset), EdgeKind::Branch),
+                (next_address, EdgeKind::Sequential),
+            ],
+            Instruction::RET | Instruction::RETI | Instruction::JMP => Vec::new(),
+            _ => vec![(next_address, EdgeKind::Sequential)],
+        }
+    }
+
+    // addresses of call targets, i.e. the subroutines used
+    pub fn call_targets(&self) -> Vec<u16> {
+        let mut targets: Vec<u16> = self
+            .edges
+            .iter()
+            .filter(|(_, _, kind)| *kind == EdgeKind::Call)
+            .map(|(_, to, _)| *to)
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+}
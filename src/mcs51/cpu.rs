@@ -1,11 +1,41 @@
+use crate::mcs51::cfg::ControlFlowGraph;
+use crate::mcs51::disassembler::{Disassembler, ListingFormat, SFR_NAMES_8051};
 use crate::mcs51::memory::Memory;
+use crate::mcs51::symbols::SymbolTable;
 use crate::mcs51::{get_bit, set_bit};
 
 use bitflags::bitflags;
 
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::rc::Rc;
 
-#[derive(Clone, Copy, Debug)]
+// length in bytes of each instruction by opcode (0xA5 is undefined), decoding reads only these
+#[rustfmt::skip]
+const OPCODE_LENGTHS: [u8; 256] = [
+    1, 2, 3, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x00
+    3, 2, 3, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x10
+    3, 2, 1, 1, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x20
+    3, 2, 1, 1, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x30
+    2, 2, 2, 3, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+    2, 2, 2, 3, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+    2, 2, 2, 3, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+    2, 2, 2, 1, 2, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, // 0x70
+    2, 2, 2, 1, 1, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, // 0x80
+    3, 2, 2, 1, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+    2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, // 0xA0
+    2, 2, 2, 1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, // 0xB0
+    2, 2, 2, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xC0
+    2, 2, 2, 1, 1, 3, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, // 0xD0
+    1, 2, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xE0
+    1, 2, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xF0
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Address {
     Code(u16),
     ExternalData(u16),
@@ -14,7 +44,75 @@ pub enum Address {
     Bit(u8),
 }
 
-#[derive(Clone, Copy, Debug)]
+// flat addresses for debuggers, the space is selected by bits 16 to 23 above the offset:
+// 0x00 code, 0x01 external data, 0x02 internal data, 0x03 SFR, 0x04 bit
+impl Address {
+    pub fn to_flat(&self) -> u64 {
+        match *self {
+            Address::Code(a) => a as u64,
+            Address::ExternalData(a) => 0x01_0000 | a as u64,
+            Address::InternalData(a) => 0x02_0000 | a as u64,
+            Address::SpecialFunctionRegister(a) => 0x03_0000 | a as u64,
+            Address::Bit(bit) => 0x04_0000 | bit as u64,
+        }
+    }
+
+    // None for an unknown space or an offset which doesn't fit its space
+    pub fn from_flat(flat: u64) -> Option<Address> {
+        let offset = flat & 0xffff;
+        match (flat >> 16, offset) {
+            (0x00, a) => Some(Address::Code(a as u16)),
+            (0x01, a) => Some(Address::ExternalData(a as u16)),
+            (0x02, a) if a <= 0xff => Some(Address::InternalData(a as u8)),
+            (0x03, a) if a <= 0xff => Some(Address::SpecialFunctionRegister(a as u8)),
+            (0x04, a) if a <= 0xff => Some(Address::Bit(a as u8)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuError {
+    // failure reported by a memory or peripheral
+    Fault(&'static str),
+    // access to an SFR which no peripheral models (strict mode only)
+    UnimplementedSfr(u8),
+    // write to code memory which isn't writable
+    WriteToRom(u16),
+    // register which can't be used with an addressing mode (e.g. @R2)
+    UnsupportedRegister(Register),
+    // addressing mode which can't be used for an access (e.g. storing to an immediate)
+    UnsupportedAddressingMode(AddressingMode),
+    // RET / RETI with the stack pointer below the register banks (strict mode only)
+    StackUnderflow(u8),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::Fault(message) => write!(f, "{}", message),
+            CpuError::UnimplementedSfr(address) => {
+                write!(f, "access to unimplemented SFR 0x{:02x}", address)
+            }
+            CpuError::WriteToRom(address) => {
+                write!(f, "write to read-only code memory at 0x{:04x}", address)
+            }
+            CpuError::UnsupportedRegister(register) => {
+                write!(f, "unsupported register {:?}", register)
+            }
+            CpuError::UnsupportedAddressingMode(mode) => {
+                write!(f, "unsupported addressing mode {:?}", mode)
+            }
+            CpuError::StackUnderflow(stack_pointer) => {
+                write!(f, "return with stack pointer at 0x{:02x}", stack_pointer)
+            }
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Register {
     R0,
     R1,
@@ -30,7 +128,7 @@ pub enum Register {
     DPTR,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AddressingMode {
     // Immediate (most immediates)
     Immediate(u8),
@@ -60,6 +158,8 @@ pub enum Instruction {
     CJNE(AddressingMode, AddressingMode, i8),
     CLR(AddressingMode),
     CPL(AddressingMode),
+    // opcode handled by a handler installed with set_custom_opcode
+    Custom(u8),
     DA,
     DEC(AddressingMode),
     DIV,
@@ -146,6 +246,85 @@ pub trait InterruptSource {
     fn pop_vector(&mut self);
 }
 
+// what a single step did, writes to the core registers (A, B, PSW, SP, DPTR) are reported as
+// writes to their SFR addresses
+#[derive(Clone, Debug)]
+pub struct StepRecord {
+    pub program_counter: u16,
+    pub instruction: Instruction,
+    pub cycles: u8,
+    pub writes: Vec<(Address, u8)>,
+}
+
+// view of the cpu handed to a custom opcode handler. program_counter holds the address of the
+// opcode on entry, the handler moves it past the instruction (or to wherever it branches).
+// A, B, PSW, SP and DPTR are only reachable through the fields, not as SFRs through memory.
+pub struct CpuContext<'a> {
+    pub program_counter: u16,
+    pub accumulator: u8,
+    pub b: u8,
+    pub psw: u8,
+    pub stack_pointer: u8,
+    pub data_pointer: u16,
+    memory: &'a mut dyn Memory,
+    writes: Vec<(Address, u8)>,
+}
+
+impl<'a> CpuContext<'a> {
+    pub fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+        self.memory.read_memory(address)
+    }
+
+    pub fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        self.writes.push((address, data));
+        self.memory.write_memory(address, data)
+    }
+}
+
+// runs a custom opcode, returns the machine cycles it took
+pub type CustomOpcodeHandler = Box<dyn FnMut(&mut CpuContext) -> Result<u8, CpuError>>;
+
+// a return which found the stack pointer somewhere else than its call left it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackImbalance {
+    // address of the call (or of the instruction an interrupt was taken before)
+    pub call_site: u16,
+    // address of the RET / RETI
+    pub return_site: u16,
+    // stack pointer right after the call pushed its return address
+    pub expected: u8,
+    // stack pointer at the return
+    pub actual: u8,
+}
+
+// stop run when an instruction writes address, or only when it writes value if one is given
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: Address,
+    pub value: Option<u8>,
+}
+
+// why run returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    // the step limit was reached
+    StepLimit,
+    // an instruction wrote a watched address, with the value written
+    Watchpoint(Address, u8),
+}
+
+// snapshot of the core registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuState {
+    pub program_counter: u16,
+    pub accumulator: u8,
+    pub b: u8,
+    pub psw: u8,
+    pub stack_pointer: u8,
+    pub data_pointer: u16,
+    pub cycles: u64,
+}
+
 pub struct CPU<A>
 where
     A: Memory + InterruptSource,
@@ -159,6 +338,31 @@ where
     memory: Rc<A>,
     ip0: bool,
     ip1: bool,
+    // no interrupt is taken right after RETI or a write to IE / IP
+    interrupt_inhibit: bool,
+    disassembler: Disassembler,
+    cycles: u64,
+    clocks: u64,
+    clocks_per_cycle: u8,
+    executed_opcodes: [u64; 256],
+    // opcode byte fetched by the last decode, counted without fetching it again
+    opcode: u8,
+    // executions per instruction address, only kept when enabled
+    pc_coverage: HashMap<u16, u64>,
+    pc_coverage_enabled: bool,
+    writes: Vec<(Address, u8)>,
+    custom_opcodes: HashMap<u8, CustomOpcodeHandler>,
+    // machine cycles reported by the last custom opcode handler
+    custom_cycles: u8,
+    // instructions executed by a single run before it gives up
+    step_limit: u64,
+    watchpoints: Vec<Watchpoint>,
+    trace: Option<Box<dyn Write>>,
+    // the write that stopped tracing, reported by finish
+    trace_error: Option<io::Error>,
+    // open calls as (call site, stack pointer after the call) while checking the stack
+    stack_check: Option<Vec<(u16, u8)>>,
+    stack_imbalances: Vec<StackImbalance>,
 }
 
 impl<A> CPU<A>
@@ -170,17 +374,253 @@ where
             flags: Flags::empty(),
             accumulator: 0,
             b: 0,
-            stack_pointer: 0,
+            stack_pointer: 0x07,
             data_pointer: 0,
             program_counter: 0,
             memory: memory,
             ip0: false,
             ip1: false,
+            interrupt_inhibit: false,
+            disassembler: Disassembler::new(SFR_NAMES_8051),
+            cycles: 0,
+            clocks: 0,
+            clocks_per_cycle: 12,
+            executed_opcodes: [0; 256],
+            opcode: 0,
+            pc_coverage: HashMap::new(),
+            pc_coverage_enabled: false,
+            writes: Vec::new(),
+            custom_opcodes: HashMap::new(),
+            custom_cycles: 0,
+            step_limit: 10_000_000,
+            watchpoints: Vec::new(),
+            trace: None,
+            trace_error: None,
+            stack_check: None,
+            stack_imbalances: Vec::new(),
         }
     }
 
-    // perform a load using a particular addressing mode
-    fn load(&mut self, mode: AddressingMode) -> Result<u8, &'static str> {
+    // active register bank (0 to 3), the RS1:RS0 bits of PSW
+    pub fn bank(&self) -> u8 {
+        self.flags.bank() >> 3
+    }
+
+    pub fn set_bank(&mut self, bank: u8) {
+        self.flags.set(Flags::BANKSELECT0, bank & 0x01 != 0);
+        self.flags.set(Flags::BANKSELECT1, bank & 0x02 != 0);
+    }
+
+    // log every executed instruction to a file, one line each:
+    //   PC BYTES A=xx PSW=xx SP=xx DPTR=xxxx ; DISASSEMBLY
+    // with the instruction bytes as one hex string ("-" for an interrupt) and the registers as
+    // they are after the instruction, so two runs can be compared line by line. the
+    // disassembly uses the part's SFR names and any symbols.
+    pub fn trace_to(&mut self, path: &Path) -> io::Result<()> {
+        self.set_trace(Some(Box::new(BufWriter::new(File::create(path)?))));
+        Ok(())
+    }
+
+    // trace to any writer instead of a file, None stops tracing
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+        self.trace_error = None;
+    }
+
+    // end of a run, flush the trace and everything the memory (peripherals) buffers. the
+    // memory is finished even when the trace fails, the first error is returned (including a
+    // write that stopped tracing part way through the run).
+    pub fn finish(&mut self) -> io::Result<()> {
+        let trace = match (self.trace_error.take(), self.trace.as_mut()) {
+            (Some(error), _) => Err(error),
+            (None, Some(trace)) => trace.flush(),
+            (None, None) => Ok(()),
+        };
+        let memory = Rc::get_mut(&mut self.memory).unwrap().finish();
+        trace.and(memory)
+    }
+
+    fn write_trace(&mut self, program_counter: u16, instruction: Instruction) {
+        let length = self.decode_instruction_length(instruction).unwrap_or(0);
+        let mem = Rc::get_mut(&mut self.memory).unwrap();
+        let mut bytes = String::new();
+        for i in 0..length {
+            match mem.read_memory(Address::Code(program_counter.wrapping_add(i))) {
+                Ok(byte) => bytes.push_str(&format!("{:02x}", byte)),
+                Err(_) => bytes.push_str("??"),
+            }
+        }
+        if bytes.is_empty() {
+            bytes.push('-');
+        }
+        let disassembly = self
+            .disassembler
+            .format_instruction(instruction, program_counter.wrapping_add(length));
+        let line = format!(
+            "{:04x} {} A={:02x} PSW={:02x} SP={:02x} DPTR={:04x} ; {}",
+            program_counter,
+            bytes,
+            self.accumulator,
+            self.flags.bits,
+            self.stack_pointer,
+            self.data_pointer,
+            disassembly
+        );
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(error) = writeln!(trace, "{}", line) {
+                self.trace = None;
+                self.trace_error = Some(error);
+            }
+        }
+    }
+
+    // check that every RET / RETI finds the stack pointer where its ACALL / LCALL (or the
+    // interrupt entry) left it, i.e. the subroutine popped everything it pushed
+    pub fn set_stack_check(&mut self, enabled: bool) {
+        self.stack_check = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // imbalances found by the stack check so far
+    pub fn stack_imbalances(&self) -> &[StackImbalance] {
+        &self.stack_imbalances
+    }
+
+    fn check_stack(&mut self, program_counter: u16, instruction: Instruction, stack_pointer: u8) {
+        let calls = match self.stack_check.as_mut() {
+            Some(calls) => calls,
+            None => return,
+        };
+        match instruction {
+            Instruction::ACALL(_) | Instruction::LCALL(_) | Instruction::Interrupt(_, _) => {
+                // iram can't hold more return addresses, calls which never return drop off
+                if calls.len() == 128 {
+                    calls.remove(0);
+                }
+                calls.push((program_counter, self.stack_pointer));
+            }
+            Instruction::RET | Instruction::RETI => {
+                if let Some((call_site, expected)) = calls.pop() {
+                    if stack_pointer != expected {
+                        self.stack_imbalances.push(StackImbalance {
+                            call_site,
+                            return_site: program_counter,
+                            expected,
+                            actual: stack_pointer,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // bound on the instructions a single run executes, so runaway firmware can't hang the host
+    pub fn set_step_limit(&mut self, step_limit: u64) {
+        self.step_limit = step_limit;
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // run handler for an opcode instead of the standard instruction set, for the extra
+    // instructions of some derivatives (e.g. in the undefined 0xA5 slot)
+    pub fn set_custom_opcode(&mut self, opcode: u8, handler: CustomOpcodeHandler) {
+        self.custom_opcodes.insert(opcode, handler);
+    }
+
+    // replace the SFR name table used when tracing (for parts with extra SFRs)
+    pub fn set_sfr_names(&mut self, sfr_names: &'static [(u8, &'static str)]) {
+        self.disassembler = Disassembler::new(sfr_names);
+    }
+
+    // symbols (e.g. from an sdcc .map) used when tracing
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.disassembler.set_symbols(symbols);
+    }
+
+    // the disassembler used when tracing, with this part's SFR names and any symbols
+    pub fn disassembler(&self) -> &Disassembler {
+        &self.disassembler
+    }
+
+    // access the memory and peripherals attached to the cpu
+    pub fn memory_mut(&mut self) -> &mut A {
+        Rc::get_mut(&mut self.memory).unwrap()
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            program_counter: self.program_counter,
+            accumulator: self.accumulator,
+            b: self.b,
+            psw: self.flags.bits,
+            stack_pointer: self.stack_pointer,
+            data_pointer: self.data_pointer,
+            cycles: self.cycles,
+        }
+    }
+
+    // machine cycles executed since reset
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // oscillator clocks per machine cycle, 12 on the classic core (6 on x2 derivatives)
+    pub fn set_clocks_per_cycle(&mut self, clocks_per_cycle: u8) {
+        self.clocks_per_cycle = clocks_per_cycle;
+    }
+
+    // oscillator clocks elapsed since reset
+    pub fn clocks(&self) -> u64 {
+        self.clocks
+    }
+
+    // how many times each opcode has been executed, for isa coverage
+    pub fn executed_opcodes(&self) -> [u64; 256] {
+        self.executed_opcodes
+    }
+
+    // count executions per instruction address (addresses never executed have no entry)
+    pub fn set_pc_coverage(&mut self, enabled: bool) {
+        self.pc_coverage_enabled = enabled;
+    }
+
+    pub fn pc_coverage(&self) -> &HashMap<u16, u64> {
+        &self.pc_coverage
+    }
+
+    // write to memory, noting the write for the step record
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        Rc::get_mut(&mut self.memory)
+            .unwrap()
+            .write_memory(address, data)?;
+        self.writes.push((address, data));
+        Ok(())
+    }
+
+    // core registers by SFR address
+    fn core_registers(&self) -> [(u8, u8); 6] {
+        let [dpl, dph] = self.data_pointer.to_le_bytes();
+        [
+            (0x81, self.stack_pointer),
+            (0x82, dpl),
+            (0x83, dph),
+            (0xD0, self.flags.bits),
+            (0xE0, self.accumulator),
+            (0xF0, self.b),
+        ]
+    }
+
+    // perform a load using a particular addressing mode. SFR accesses resolve in a fixed order:
+    // the core registers (SP, DPL, DPH, PSW, ACC, B and their bits) are fields of the cpu and
+    // never reach memory, everything else goes to the memory, where the soc routes it to the
+    // peripheral owning the address before falling back to plain storage
+    fn load(&mut self, mode: AddressingMode) -> Result<u8, CpuError> {
         let mem = Rc::get_mut(&mut self.memory).unwrap();
         match mode {
             AddressingMode::Immediate(imm8) => Ok(imm8),
@@ -195,7 +635,7 @@ where
                 Register::R5 => mem.read_memory(Address::InternalData(self.flags.bank() + 5)),
                 Register::R6 => mem.read_memory(Address::InternalData(self.flags.bank() + 6)),
                 Register::R7 => mem.read_memory(Address::InternalData(self.flags.bank() + 7)),
-                _ => Err("unsupported register"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
             AddressingMode::Bit(bit) => {
                 // 8051 bit values occupy 0x20 to 0x2F
@@ -244,7 +684,7 @@ where
                     let address = mem.read_memory(Address::InternalData(self.flags.bank() + 1))?;
                     mem.read_memory(Address::InternalData(address))
                 }
-                _ => Err("unsupported register for indirect load"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
             AddressingMode::IndirectExternal(register) => match register {
                 // port 2 forms the upper 8 bits of an indirect external access with R0/1
@@ -263,22 +703,39 @@ where
                     mem.read_memory(Address::ExternalData(u16::from_le_bytes(address)))
                 }
                 Register::DPTR => mem.read_memory(Address::ExternalData(self.data_pointer)),
-                _ => Err("unsupported register for indirect load (external)"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
             AddressingMode::IndirectCode(register) => match register {
-                Register::DPTR => {
-                    mem.read_memory(Address::Code(self.data_pointer + (self.accumulator as u16)))
-                }
+                Register::DPTR => mem.read_memory(Address::Code(
+                    self.data_pointer.wrapping_add(self.accumulator as u16),
+                )),
                 Register::PC => mem.read_memory(Address::Code(
-                    self.program_counter + (self.accumulator as u16) + 1,
+                    self.program_counter
+                        .wrapping_add(self.accumulator as u16)
+                        .wrapping_add(1),
                 )),
-                _ => Err("unsupported register for indirect load (code)"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
         }
     }
 
+    // load for a read-modify-write instruction (ANL, ORL, XRL, CPL, JBC, DJNZ, INC and DEC),
+    // ports and their bits read the latch rather than the pins
+    fn load_latch(&mut self, mode: AddressingMode) -> Result<u8, CpuError> {
+        let mem = Rc::get_mut(&mut self.memory).unwrap();
+        match mode {
+            AddressingMode::Direct(address @ (0x80 | 0x90 | 0xA0 | 0xB0)) => {
+                mem.read_latch(Address::SpecialFunctionRegister(address))
+            }
+            AddressingMode::Bit(bit @ (0x80..=0x87 | 0x90..=0x97 | 0xA0..=0xA7 | 0xB0..=0xB7)) => {
+                mem.read_latch(Address::Bit(bit))
+            }
+            _ => self.load(mode),
+        }
+    }
+
     // perform a store using an addressing mode
-    fn store(&mut self, mode: AddressingMode, data: u8) -> Result<(), &'static str> {
+    fn store(&mut self, mode: AddressingMode, data: u8) -> Result<(), CpuError> {
         let mem = Rc::get_mut(&mut self.memory).unwrap();
         match mode {
             AddressingMode::Register(register) => match register {
@@ -291,36 +748,36 @@ where
                     Ok(())
                 }
                 Register::R0 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 0), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 0), data)
                 }
                 Register::R1 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 1), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 1), data)
                 }
                 Register::R2 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 2), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 2), data)
                 }
                 Register::R3 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 3), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 3), data)
                 }
                 Register::R4 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 4), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 4), data)
                 }
                 Register::R5 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 5), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 5), data)
                 }
                 Register::R6 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 6), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 6), data)
                 }
                 Register::R7 => {
-                    mem.write_memory(Address::InternalData(self.flags.bank() + 7), data)
+                    self.write_memory(Address::InternalData(self.flags.bank() + 7), data)
                 }
-                _ => Err("unsupported register"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
             AddressingMode::Bit(bit) => {
                 // 8051 bit values occupy 0x20 to 0x2F
                 if bit < 128 {
                     let octet = mem.read_memory(Address::InternalData(0x20 + (bit >> 3)))?;
-                    mem.write_memory(
+                    self.write_memory(
                         Address::InternalData(0x20 + (bit >> 3)),
                         set_bit(octet, bit & 7, data != 0),
                     )
@@ -339,14 +796,14 @@ where
                             self.b = set_bit(self.b, bit & 7, data != 0);
                             Ok(())
                         }
-                        _ => mem.write_memory(Address::Bit(bit), data),
+                        _ => self.write_memory(Address::Bit(bit), data),
                     }
                 }
             }
             AddressingMode::Direct(address) => {
                 // 128-byte iram of 8051 vs SFR (upper 128 on 8052 can only be used via indirect)
                 if address < 128 {
-                    mem.write_memory(Address::InternalData(address), data)
+                    self.write_memory(Address::InternalData(address), data)
                 } else {
                     match address {
                         0x81 => {
@@ -375,20 +832,20 @@ where
                             self.b = data;
                             Ok(())
                         }
-                        _ => mem.write_memory(Address::SpecialFunctionRegister(address), data),
+                        _ => self.write_memory(Address::SpecialFunctionRegister(address), data),
                     }
                 }
             }
             AddressingMode::Indirect(register) => match register {
                 Register::R0 => {
                     let address = mem.read_memory(Address::InternalData(self.flags.bank() + 0))?;
-                    mem.write_memory(Address::InternalData(address), data)
+                    self.write_memory(Address::InternalData(address), data)
                 }
                 Register::R1 => {
                     let address = mem.read_memory(Address::InternalData(self.flags.bank() + 1))?;
-                    mem.write_memory(Address::InternalData(address), data)
+                    self.write_memory(Address::InternalData(address), data)
                 }
-                _ => Err("unsupported register for indirect store"),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
             AddressingMode::IndirectExternal(register) => match register {
                 // port 2 forms the upper 8 bits of an indirect external access with R0/1
@@ -397,28 +854,165 @@ where
                         mem.read_memory(Address::InternalData(self.flags.bank() + 0))?,
                         mem.read_memory(Address::SpecialFunctionRegister(0xA0))?,
                     ];
-                    mem.write_memory(Address::ExternalData(u16::from_le_bytes(address)), data)
+                    self.write_memory(Address::ExternalData(u16::from_le_bytes(address)), data)
                 }
                 Register::R1 => {
                     let address = [
                         mem.read_memory(Address::InternalData(self.flags.bank() + 1))?,
                         mem.read_memory(Address::SpecialFunctionRegister(0xA0))?,
                     ];
-                    mem.write_memory(Address::ExternalData(u16::from_le_bytes(address)), data)
+                    self.write_memory(Address::ExternalData(u16::from_le_bytes(address)), data)
                 }
-                Register::DPTR => mem.write_memory(Address::ExternalData(self.data_pointer), data),
-                _ => Err("unsupported register for indirect store"),
+                Register::DPTR => self.write_memory(Address::ExternalData(self.data_pointer), data),
+                _ => Err(CpuError::UnsupportedRegister(register)),
             },
-            _ => Err("unsupported addressing mode (store)"),
+            _ => Err(CpuError::UnsupportedAddressingMode(mode)),
         }
     }
 
-    // decode the next instruction
-    fn decode_next_opcode(&mut self) -> Result<Instruction, &'static str> {
+    // perform a 16-bit load, only DPTR is a 16-bit operand
+    fn load16(&mut self, mode: AddressingMode) -> Result<u16, CpuError> {
+        match mode {
+            AddressingMode::Register(Register::DPTR) => Ok(self.data_pointer),
+            _ => Err(CpuError::UnsupportedAddressingMode(mode)),
+        }
+    }
+
+    // perform a 16-bit store
+    fn store16(&mut self, mode: AddressingMode, data: u16) -> Result<(), CpuError> {
+        match mode {
+            AddressingMode::Register(Register::DPTR) => {
+                self.data_pointer = data;
+                Ok(())
+            }
+            _ => Err(CpuError::UnsupportedAddressingMode(mode)),
+        }
+    }
+
+    // where an operand lives with the current registers, e.g. to preview what an instruction
+    // will access before stepping it. immediates (and the pc) have no address, A, C and DPTR
+    // resolve to their SFR / bit (DPL for DPTR).
+    pub fn effective_address(&mut self, mode: AddressingMode) -> Option<Address> {
+        let register = |register| match register {
+            Register::R0 => Some(0),
+            Register::R1 => Some(1),
+            Register::R2 => Some(2),
+            Register::R3 => Some(3),
+            Register::R4 => Some(4),
+            Register::R5 => Some(5),
+            Register::R6 => Some(6),
+            Register::R7 => Some(7),
+            _ => None,
+        };
+        match mode {
+            AddressingMode::Immediate(_) => None,
+            AddressingMode::Register(Register::A) => Some(Address::SpecialFunctionRegister(0xE0)),
+            AddressingMode::Register(Register::C) => Some(Address::Bit(0xD7)),
+            AddressingMode::Register(Register::DPTR) => {
+                Some(Address::SpecialFunctionRegister(0x82))
+            }
+            AddressingMode::Register(r) => {
+                Some(Address::InternalData(self.flags.bank() + register(r)?))
+            }
+            AddressingMode::Bit(bit) | AddressingMode::NotBit(bit) => Some(Address::Bit(bit)),
+            AddressingMode::Direct(address) if address < 0x80 => {
+                Some(Address::InternalData(address))
+            }
+            AddressingMode::Direct(address) => Some(Address::SpecialFunctionRegister(address)),
+            // only R0 and R1 can be used as pointers
+            AddressingMode::Indirect(r @ (Register::R0 | Register::R1)) => Some(
+                Address::InternalData(self.load(AddressingMode::Register(r)).ok()?),
+            ),
+            AddressingMode::Indirect(_) => None,
+            AddressingMode::IndirectExternal(Register::DPTR) => {
+                Some(Address::ExternalData(self.data_pointer))
+            }
+            AddressingMode::IndirectExternal(r @ (Register::R0 | Register::R1)) => {
+                let address = [
+                    self.load(AddressingMode::Register(r)).ok()?,
+                    self.load(AddressingMode::Direct(0xA0)).ok()?,
+                ];
+                Some(Address::ExternalData(u16::from_le_bytes(address)))
+            }
+            AddressingMode::IndirectExternal(_) => None,
+            AddressingMode::IndirectCode(Register::DPTR) => Some(Address::Code(
+                self.data_pointer.wrapping_add(self.accumulator as u16),
+            )),
+            AddressingMode::IndirectCode(Register::PC) => Some(Address::Code(
+                self.program_counter
+                    .wrapping_add(self.accumulator as u16)
+                    .wrapping_add(1),
+            )),
+            AddressingMode::IndirectCode(_) => None,
+        }
+    }
+
+    // pop the return address of a RET / RETI, high byte first
+    fn pop_return_address(&mut self) -> Result<u16, CpuError> {
+        // in strict mode (a switch on the memory, see Memory::strict) popping the return
+        // address out of register bank 0 is an error rather than wrapping
+        if self.memory.strict() && self.stack_pointer < 0x08 {
+            return Err(CpuError::StackUnderflow(self.stack_pointer));
+        }
         let mem = Rc::get_mut(&mut self.memory).unwrap();
-        let opcode = mem.read_memory(Address::Code(self.program_counter))?;
-        let arg1 = mem.read_memory(Address::Code(self.program_counter + 1));
-        let arg2 = mem.read_memory(Address::Code(self.program_counter + 2));
+        let address = u16::from_le_bytes([
+            mem.read_memory(Address::InternalData(self.stack_pointer.wrapping_sub(1)))?,
+            mem.read_memory(Address::InternalData(self.stack_pointer))?,
+        ]);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(2);
+        Ok(address)
+    }
+
+    // add to the accumulator, C, AC and OV come from the carries out of bits 7, 3 and 6
+    fn add(&mut self, data: u8, carry: u8) {
+        let carry3 = (self.accumulator & 0x0f) + (data & 0x0f) + carry > 0x0f;
+        let carry6 = (self.accumulator & 0x7f) + (data & 0x7f) + carry > 0x7f;
+        let carry7 = (self.accumulator as u16) + (data as u16) + (carry as u16) > 0xff;
+        self.accumulator = self.accumulator.wrapping_add(data).wrapping_add(carry);
+
+        // flags, signed overflow is a carry out of exactly one of bits 6 and 7
+        self.flags.set(Flags::CARRY, carry7);
+        self.flags.set(Flags::AUXILIARYCARRY, carry3);
+        self.flags.set(Flags::OVERFLOW, carry6 ^ carry7);
+    }
+
+    // subtract with borrow from the accumulator, C, AC and OV come from the borrows into bits
+    // 7, 3 and 6
+    fn subtract(&mut self, data: u8, borrow: u8) {
+        let borrow3 = (data & 0x0f) + borrow > (self.accumulator & 0x0f);
+        let borrow6 = (data & 0x7f) + borrow > (self.accumulator & 0x7f);
+        let borrow7 = (data as u16) + (borrow as u16) > (self.accumulator as u16);
+        self.accumulator = self.accumulator.wrapping_sub(data).wrapping_sub(borrow);
+
+        // flags, signed overflow is a borrow into exactly one of bits 6 and 7
+        self.flags.set(Flags::CARRY, borrow7);
+        self.flags.set(Flags::AUXILIARYCARRY, borrow3);
+        self.flags.set(Flags::OVERFLOW, borrow6 ^ borrow7);
+    }
+
+    // decode the instruction at an address
+    fn decode_opcode(&mut self, address: u16) -> Result<Instruction, CpuError> {
+        let mem = Rc::get_mut(&mut self.memory).unwrap();
+        let opcode = mem.read_memory(Address::Code(address))?;
+        self.opcode = opcode;
+
+        // installed handlers take precedence over the standard instruction set
+        if self.custom_opcodes.contains_key(&opcode) {
+            return Ok(Instruction::Custom(opcode));
+        }
+
+        // fetch only the operand bytes the instruction has
+        let length = OPCODE_LENGTHS[opcode as usize];
+        let arg1 = if length > 1 {
+            mem.read_memory(Address::Code(address.wrapping_add(1)))
+        } else {
+            Err(CpuError::Fault("instruction has no operand"))
+        };
+        let arg2 = if length > 2 {
+            mem.read_memory(Address::Code(address.wrapping_add(2)))
+        } else {
+            Err(CpuError::Fault("instruction has no operand"))
+        };
 
         // decode instruction
         match opcode {
@@ -688,7 +1282,8 @@ where
                 AddressingMode::Direct(arg1?),
                 AddressingMode::Register(register_from_op(opcode)),
             )),
-            // MOV DPTR, #data16
+            // MOV DPTR, #data16 (the immediate is stored high byte first, unlike DPTR itself
+            // which is DPL at 0x82 and DPH at 0x83)
             0x90 => {
                 let pointer = ((arg1? as u16) << 8) | (arg2? as u16);
                 Ok(Instruction::LoadDptr(pointer))
@@ -729,7 +1324,7 @@ where
             // MUL AB
             0xA4 => Ok(Instruction::MUL),
             // Undefined instruction
-            0xA5 => Err("undefined instruction opcode"),
+            0xA5 => Err(CpuError::Fault("undefined instruction opcode")),
             // MOV @R0, iram addr
             0xA6 => Ok(Instruction::MOV(
                 AddressingMode::Indirect(Register::R0),
@@ -902,7 +1497,12 @@ where
     }
 
     // decode the next instruction or interrupt
-    fn decode_next_instruction(&mut self) -> Result<Instruction, &'static str> {
+    fn decode_next_instruction(&mut self) -> Result<Instruction, CpuError> {
+        // at least one more instruction executes after RETI or a write to IE / IP
+        if self.interrupt_inhibit {
+            return self.decode_opcode(self.program_counter);
+        }
+
         // check if there is an interrupt available
         match Rc::get_mut(&mut self.memory).unwrap().peek_vector() {
             Some((vector, priority)) => {
@@ -919,15 +1519,122 @@ where
                 if priority >= min_priority {
                     Ok(Instruction::Interrupt(vector, priority))
                 } else {
-                    self.decode_next_opcode()
+                    self.decode_opcode(self.program_counter)
+                }
+            }
+            None => self.decode_opcode(self.program_counter),
+        }
+    }
+
+    // decode count instructions from start as (address, instruction, bytes) for a listing,
+    // stopping early at memory which can't be decoded
+    pub fn disassemble_range(
+        &mut self,
+        start: u16,
+        count: usize,
+    ) -> Vec<(u16, Instruction, Vec<u8>)> {
+        let mut listing = Vec::with_capacity(count);
+        let mut address = start;
+        for _ in 0..count {
+            let instruction = match self.decode_opcode(address) {
+                Ok(instruction) => instruction,
+                Err(_) => break,
+            };
+            let length = match self.decode_instruction_length(instruction) {
+                Ok(length) => length,
+                Err(_) => break,
+            };
+            let mem = Rc::get_mut(&mut self.memory).unwrap();
+            let bytes: Result<Vec<u8>, CpuError> = (0..length)
+                .map(|i| mem.read_memory(Address::Code(address.wrapping_add(i))))
+                .collect();
+            match bytes {
+                Ok(bytes) => listing.push((address, instruction, bytes)),
+                Err(_) => break,
+            }
+            address = address.wrapping_add(length);
+        }
+        listing
+    }
+
+    // listing of the code from start up to (not including) end, with a label line wherever a
+    // code symbol is defined. bytes which don't decode (e.g. undefined opcodes or instructions
+    // running past end) are listed as .db, the listing stops early at unreadable memory.
+    pub fn disassemble_image(&mut self, start: u16, end: u16) -> String {
+        let format = ListingFormat::default();
+        let mut listing = String::new();
+        let mut address = start as u32;
+        while address < end as u32 {
+            let a = address as u16;
+            if let Some(label) = self.disassembler.code_label(a) {
+                listing.push_str(&format!("{}:\n", label));
+            }
+            let instruction = self.decode_opcode(a).and_then(|instruction| {
+                Ok((instruction, self.decode_instruction_length(instruction)?))
+            });
+            let mem = Rc::get_mut(&mut self.memory).unwrap();
+            let line = match instruction {
+                Ok((instruction, length)) if address + length as u32 <= end as u32 => {
+                    let bytes: Result<Vec<u8>, CpuError> = (0..length)
+                        .map(|i| mem.read_memory(Address::Code(a.wrapping_add(i))))
+                        .collect();
+                    match bytes {
+                        Ok(bytes) => {
+                            address += length as u32;
+                            self.disassembler
+                                .format_listing_line(format, a, &bytes, instruction)
+                        }
+                        Err(_) => break,
+                    }
                 }
+                _ => match mem.read_memory(Address::Code(a)) {
+                    Ok(byte) => {
+                        address += 1;
+                        self.disassembler.format_data_line(format, a, byte)
+                    }
+                    Err(_) => break,
+                },
+            };
+            listing.push_str(&line);
+            listing.push('\n');
+        }
+        listing
+    }
+
+    // follow the code reachable from entry without executing it
+    pub fn build_cfg(&mut self, entry: u16) -> ControlFlowGraph {
+        let mut cfg = ControlFlowGraph::default();
+        let mut pending = vec![entry];
+        while let Some(address) = pending.pop() {
+            if cfg.instructions.contains_key(&address) || cfg.undecodable.contains(&address) {
+                continue;
+            }
+            let decoded = self.decode_opcode(address).and_then(|instruction| {
+                Ok((instruction, self.decode_instruction_length(instruction)?))
+            });
+            let (instruction, length) = match decoded {
+                Ok(decoded) => decoded,
+                Err(_) => {
+                    cfg.undecodable.push(address);
+                    continue;
+                }
+            };
+            cfg.instructions.insert(address, (instruction, length));
+            if let Instruction::JMP = instruction {
+                cfg.computed_jumps.push(address);
+            }
+            for (to, kind) in
+                ControlFlowGraph::successors(instruction, address.wrapping_add(length))
+            {
+                cfg.edges.push((address, to, kind));
+                pending.push(to);
             }
-            None => self.decode_next_opcode(),
         }
+        cfg
     }
 
     // decode length of instruction
-    fn decode_instruction_length(&self, instruction: Instruction) -> Result<u16, &'static str> {
+    fn decode_instruction_length(&self, instruction: Instruction) -> Result<u16, CpuError> {
         match instruction {
             Instruction::ACALL(_) => Ok(2),
             Instruction::ADD(operand2) => match operand2 {
@@ -963,6 +1670,8 @@ where
                 AddressingMode::Register(_) => Ok(1),
                 _ => Ok(2),
             },
+            // only the opcode is known, the handler advances the pc itself
+            Instruction::Custom(_) => Ok(1),
             Instruction::DA => Ok(1),
             Instruction::DEC(address) => match address {
                 AddressingMode::Indirect(_) => Ok(1),
@@ -1031,7 +1740,10 @@ where
             Instruction::RLC => Ok(1),
             Instruction::RR => Ok(1),
             Instruction::RRC => Ok(1),
-            Instruction::SETB(_) => Ok(2),
+            Instruction::SETB(address) => match address {
+                AddressingMode::Register(_) => Ok(1),
+                _ => Ok(2),
+            },
             Instruction::SJMP(_) => Ok(2),
             Instruction::SUBB(operand2) => match operand2 {
                 AddressingMode::Indirect(_) => Ok(1),
@@ -1065,69 +1777,115 @@ where
         }
     }
 
-    // execute an instruction
-    pub fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), &'static str> {
+    // decode machine cycles taken by an instruction
+    fn decode_instruction_cycles(&self, instruction: Instruction) -> u8 {
+        match instruction {
+            Instruction::ACALL(_) => 2,
+            Instruction::AJMP(_) => 2,
+            Instruction::ANL(operand1, operand2)
+            | Instruction::ORL(operand1, operand2)
+            | Instruction::XRL(operand1, operand2) => match (operand1, operand2) {
+                (AddressingMode::Register(Register::C), _) => 2,
+                (AddressingMode::Direct(_), AddressingMode::Immediate(_)) => 2,
+                _ => 1,
+            },
+            Instruction::CJNE(_, _, _) => 2,
+            Instruction::Custom(_) => self.custom_cycles,
+            Instruction::DIV => 4,
+            Instruction::DJNZ(_, _) => 2,
+            Instruction::INC(AddressingMode::Register(Register::DPTR)) => 2,
+            Instruction::Interrupt(_, _) => 2,
+            Instruction::JB(_, _) => 2,
+            Instruction::JBC(_, _) => 2,
+            Instruction::JC(_) => 2,
+            Instruction::JMP => 2,
+            Instruction::JNB(_, _) => 2,
+            Instruction::JNC(_) => 2,
+            Instruction::JNZ(_) => 2,
+            Instruction::JZ(_) => 2,
+            Instruction::LCALL(_) => 2,
+            Instruction::LJMP(_) => 2,
+            Instruction::LoadDptr(_) => 2,
+            Instruction::MOV(operand1, operand2) => match (operand1, operand2) {
+                (AddressingMode::Register(Register::A), _) => 1,
+                (AddressingMode::Register(Register::C), _) => 1,
+                (AddressingMode::Bit(_), _) => 2,
+                (_, AddressingMode::Direct(_)) => 2,
+                (AddressingMode::Direct(_), AddressingMode::Register(Register::A)) => 1,
+                (AddressingMode::Direct(_), _) => 2,
+                _ => 1,
+            },
+            Instruction::MOVC(_) => 2,
+            Instruction::MOVX(_, _) => 2,
+            Instruction::MUL => 4,
+            Instruction::POP(_) => 2,
+            Instruction::PUSH(_) => 2,
+            Instruction::RET => 2,
+            Instruction::RETI => 2,
+            Instruction::SJMP(_) => 2,
+            _ => 1,
+        }
+    }
+
+    // execute an instruction at the pc as if it had been fetched from there, taking no time.
+    // on an error the pc is left at the faulting instruction, anything the instruction did
+    // before failing (e.g. a push of the return address) is kept
+    pub fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), CpuError> {
         let length = self.decode_instruction_length(instruction)?;
-        let mut next_program_counter = self.program_counter + length;
-        println!("{:04x}: {:?}", self.program_counter, instruction);
+        self.execute_semantics(instruction, length)
+    }
+
+    // execute an instruction of length bytes at the pc (the length sets the next pc and the
+    // base of relative branches) and let the peripherals run for the cycles it takes. returns
+    // the machine cycles taken.
+    pub fn execute(&mut self, instruction: Instruction, length: u16) -> Result<u8, CpuError> {
+        self.execute_semantics(instruction, length)?;
+        let cycles = self.decode_instruction_cycles(instruction);
+
+        // peripherals advance once per machine cycle
+        let mem = Rc::get_mut(&mut self.memory).unwrap();
+        for _ in 0..cycles {
+            mem.tick();
+        }
+        self.cycles += cycles as u64;
+        self.clocks += cycles as u64 * self.clocks_per_cycle as u64;
+        Ok(cycles)
+    }
+
+    fn execute_semantics(&mut self, instruction: Instruction, length: u16) -> Result<(), CpuError> {
+        self.writes.clear();
+        let mut next_program_counter = self.program_counter.wrapping_add(length);
 
         let result = match instruction {
             Instruction::ACALL(address) => {
-                if self.stack_pointer >= 127 {
-                    panic!("stack overflow in ACALL");
-                }
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 1),
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(1)),
                     next_program_counter.to_le_bytes()[0],
                 )?;
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 2),
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(2)),
                     next_program_counter.to_le_bytes()[1],
                 )?;
-                self.stack_pointer = self.stack_pointer + 2;
-                next_program_counter = (self.program_counter & 0xF800) | address;
+                self.stack_pointer = self.stack_pointer.wrapping_add(2);
+                next_program_counter = (next_program_counter & 0xF800) | address;
                 Ok(())
             }
             Instruction::ADD(operand2) => {
                 let data = self.load(operand2)?;
-                let result = (self.accumulator as u16) + (data as u16);
-                let half_result = (self.accumulator & 0xf) + (data & 0xf);
-                let signed_result = (self.accumulator & 0x7f) + (data & 0x7f);
-                self.accumulator = result as u8;
-
-                // flags
-                self.flags.set(Flags::CARRY, result > 255);
-                self.flags.set(Flags::AUXILIARYCARRY, half_result > 16);
-                self.flags.set(
-                    Flags::OVERFLOW,
-                    self.flags.contains(Flags::CARRY) ^ (signed_result > 127),
-                );
+                self.add(data, 0);
                 Ok(())
             }
             Instruction::ADDC(operand2) => {
                 let data = self.load(operand2)?;
-                let result =
-                    (self.accumulator as u16) + (data as u16) + (self.flags.carry() as u16);
-                let half_result = (self.accumulator & 0xf) + (data & 0xf) + self.flags.carry();
-                let signed_result = (self.accumulator & 0x7f) + (data & 0x7f) + self.flags.carry();
-                self.accumulator = result as u8;
-
-                // flags
-                self.flags.set(Flags::CARRY, result > 255);
-                self.flags.set(Flags::AUXILIARYCARRY, half_result > 16);
-                self.flags.set(
-                    Flags::OVERFLOW,
-                    self.flags.contains(Flags::CARRY) ^ (signed_result > 127),
-                );
+                self.add(data, self.flags.carry());
                 Ok(())
             }
             Instruction::AJMP(address) => {
-                next_program_counter = (self.program_counter & 0xF800) | address;
+                next_program_counter = (next_program_counter & 0xF800) | address;
                 Ok(())
             }
             Instruction::ANL(operand1, operand2) => {
-                let data = self.load(operand1)? & self.load(operand2)?;
+                let data = self.load_latch(operand1)? & self.load(operand2)?;
                 self.store(operand1, data)
             }
             Instruction::CJNE(operand1, operand2, offset) => {
@@ -1135,14 +1893,43 @@ where
                 let operand2 = self.load(operand2)?;
                 self.flags.set(Flags::CARRY, operand1 < operand2);
                 if operand1 != operand2 {
-                    next_program_counter = ((next_program_counter as i16) + (offset as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(offset as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::CLR(address) => self.store(address, 0),
             Instruction::CPL(address) => {
-                let data = self.load(address)?;
-                self.store(address, !data)
+                let data = self.load_latch(address)?;
+                match address {
+                    AddressingMode::Register(Register::A) => self.store(address, !data),
+                    // C and bits load as 0 / 1
+                    _ => self.store(address, data ^ 0x01),
+                }
+            }
+            Instruction::Custom(opcode) => {
+                let handler = self
+                    .custom_opcodes
+                    .get_mut(&opcode)
+                    .ok_or(CpuError::Fault("undefined instruction opcode"))?;
+                let mut context = CpuContext {
+                    program_counter: self.program_counter,
+                    accumulator: self.accumulator,
+                    b: self.b,
+                    psw: self.flags.bits,
+                    stack_pointer: self.stack_pointer,
+                    data_pointer: self.data_pointer,
+                    memory: Rc::get_mut(&mut self.memory).unwrap(),
+                    writes: Vec::new(),
+                };
+                let result = handler(&mut context);
+                next_program_counter = context.program_counter;
+                self.accumulator = context.accumulator;
+                self.b = context.b;
+                self.flags.bits = context.psw;
+                self.stack_pointer = context.stack_pointer;
+                self.data_pointer = context.data_pointer;
+                self.writes.extend(context.writes);
+                result.map(|cycles| self.custom_cycles = cycles)
             }
             Instruction::DA => {
                 let mut result = self.accumulator as u16;
@@ -1162,132 +1949,124 @@ where
                 Ok(())
             }
             Instruction::DEC(address) => {
-                let data = self.load(address)?;
-                self.store(address, data - 1)
+                let data = self.load_latch(address)?;
+                self.store(address, data.wrapping_sub(1))
             }
             Instruction::DIV => {
-                self.flags.set(Flags::OVERFLOW, self.b == 0);
                 self.flags.remove(Flags::CARRY);
-                if self.b != 0 {
-                    let quotient = self.accumulator / self.b;
-                    let remainder = self.accumulator % self.b;
-                    self.accumulator = quotient;
-                    self.b = remainder;
+                match (
+                    self.accumulator.checked_div(self.b),
+                    self.accumulator.checked_rem(self.b),
+                ) {
+                    (Some(quotient), Some(remainder)) => {
+                        self.accumulator = quotient;
+                        self.b = remainder;
+                        self.flags.remove(Flags::OVERFLOW);
+                    }
+                    // divide by zero, the datasheet leaves A and B undefined and sets OV. they
+                    // are kept unchanged here so the result is predictable.
+                    _ => self.flags.insert(Flags::OVERFLOW),
                 }
                 Ok(())
             }
             Instruction::DJNZ(address, offset) => {
-                let mut data = self.load(address)?;
-                data = data - 1;
+                let mut data = self.load_latch(address)?;
+                data = data.wrapping_sub(1);
                 self.store(address, data)?;
                 if data != 0 {
-                    next_program_counter = ((next_program_counter as i16) + (offset as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(offset as i16 as u16);
                 }
                 Ok(())
             }
-            Instruction::INC(address) => {
-                if let AddressingMode::Register(Register::DPTR) = address {
-                    self.data_pointer = self.data_pointer + 1;
-                    Ok(())
-                } else {
-                    let data = self.load(address)?;
-                    self.store(address, data + 1)
+            Instruction::INC(address) => match address {
+                AddressingMode::Register(Register::DPTR) => {
+                    let data = self.load16(address)?;
+                    self.store16(address, data.wrapping_add(1))
                 }
-            }
-            Instruction::Interrupt(address, priority) => {
-                if self.stack_pointer >= 127 {
-                    panic!("stack overflow in Interrupt");
+                _ => {
+                    let data = self.load_latch(address)?;
+                    self.store(address, data.wrapping_add(1))
                 }
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 1),
+            },
+            Instruction::Interrupt(address, priority) => {
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(1)),
                     next_program_counter.to_le_bytes()[0],
                 )?;
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 2),
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(2)),
                     next_program_counter.to_le_bytes()[1],
                 )?;
-                self.stack_pointer = self.stack_pointer + 2;
+                self.stack_pointer = self.stack_pointer.wrapping_add(2);
                 next_program_counter = address;
                 match priority {
                     0 => self.ip0 = true,
                     1 => self.ip1 = true,
-                    _ => panic!("unsupported priority"),
+                    _ => return Err(CpuError::Fault("unsupported interrupt priority")),
                 }
-                mem.pop_vector();
+                Rc::get_mut(&mut self.memory).unwrap().pop_vector();
                 Ok(())
             }
             Instruction::JB(bit, address) => {
                 let data = self.load(bit)?;
                 if data != 0 {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JBC(bit, address) => {
-                let data = self.load(bit)?;
+                let data = self.load_latch(bit)?;
                 if data != 0 {
                     self.store(bit, 0)?;
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JC(address) => {
                 if self.flags.contains(Flags::CARRY) {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JMP => {
-                next_program_counter = self.data_pointer + self.accumulator as u16;
+                next_program_counter = self.data_pointer.wrapping_add(self.accumulator as u16);
                 Ok(())
             }
             Instruction::JNB(bit, address) => {
                 let data = self.load(bit)?;
                 if data == 0 {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JNC(address) => {
                 if !self.flags.contains(Flags::CARRY) {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JNZ(address) => {
                 if self.accumulator != 0 {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::JZ(address) => {
                 if self.accumulator == 0 {
-                    next_program_counter =
-                        ((next_program_counter as i16) + (address as i16)) as u16;
+                    next_program_counter = next_program_counter.wrapping_add(address as i16 as u16);
                 }
                 Ok(())
             }
             Instruction::LCALL(address) => {
-                if self.stack_pointer >= 127 {
-                    panic!("stack overflow in LCALL");
-                }
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 1),
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(1)),
                     next_program_counter.to_le_bytes()[0],
                 )?;
-                mem.write_memory(
-                    Address::InternalData(self.stack_pointer + 2),
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(2)),
                     next_program_counter.to_le_bytes()[1],
                 )?;
-                self.stack_pointer = self.stack_pointer + 2;
+                self.stack_pointer = self.stack_pointer.wrapping_add(2);
                 next_program_counter = address;
                 Ok(())
             }
@@ -1317,41 +2096,30 @@ where
             }
             Instruction::NOP => Ok(()),
             Instruction::ORL(operand1, operand2) => {
-                let data = self.load(operand1)? | self.load(operand2)?;
+                let data = self.load_latch(operand1)? | self.load(operand2)?;
                 self.store(operand1, data)
             }
             Instruction::POP(address) => {
                 let mem = Rc::get_mut(&mut self.memory).unwrap();
                 let data = mem.read_memory(Address::InternalData(self.stack_pointer))?;
-                self.stack_pointer = self.stack_pointer - 1;
+                self.stack_pointer = self.stack_pointer.wrapping_sub(1);
                 self.store(address, data)
             }
             Instruction::PUSH(address) => {
-                if self.stack_pointer >= 127 {
-                    panic!("stack overflow in PUSH");
-                }
                 let data = self.load(address)?;
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                mem.write_memory(Address::InternalData(self.stack_pointer + 1), data)?;
-                self.stack_pointer = self.stack_pointer + 1;
+                self.write_memory(
+                    Address::InternalData(self.stack_pointer.wrapping_add(1)),
+                    data,
+                )?;
+                self.stack_pointer = self.stack_pointer.wrapping_add(1);
                 Ok(())
             }
             Instruction::RET => {
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                next_program_counter = u16::from_le_bytes([
-                    mem.read_memory(Address::InternalData(self.stack_pointer - 1))?,
-                    mem.read_memory(Address::InternalData(self.stack_pointer))?,
-                ]);
-                self.stack_pointer = self.stack_pointer - 2;
+                next_program_counter = self.pop_return_address()?;
                 Ok(())
             }
             Instruction::RETI => {
-                let mem = Rc::get_mut(&mut self.memory).unwrap();
-                next_program_counter = u16::from_le_bytes([
-                    mem.read_memory(Address::InternalData(self.stack_pointer - 1))?,
-                    mem.read_memory(Address::InternalData(self.stack_pointer))?,
-                ]);
-                self.stack_pointer = self.stack_pointer - 2;
+                next_program_counter = self.pop_return_address()?;
                 if self.ip1 == true {
                     self.ip1 = false;
                 } else if self.ip0 == true {
@@ -1382,25 +2150,12 @@ where
             }
             Instruction::SETB(address) => self.store(address, 1),
             Instruction::SJMP(offset) => {
-                next_program_counter = ((next_program_counter as i16) + (offset as i16)) as u16;
+                next_program_counter = next_program_counter.wrapping_add(offset as i16 as u16);
                 Ok(())
             }
             Instruction::SUBB(operand2) => {
                 let data = self.load(operand2)?;
-                let result =
-                    (self.accumulator as u16) - (data as u16) - (self.flags.carry() as u16);
-                // flags
-                self.flags.set(
-                    Flags::AUXILIARYCARRY,
-                    ((data & 0xf) + self.flags.carry()) > (self.accumulator & 0xf),
-                );
-                self.flags
-                    .set(Flags::CARRY, (data + self.flags.carry()) > self.accumulator);
-                self.flags.set(
-                    Flags::OVERFLOW,
-                    ((result as i16) > 127) || ((result as i16) < -128),
-                );
-                self.accumulator = result as u8;
+                self.subtract(data, self.flags.carry());
                 Ok(())
             }
             Instruction::SWAP => {
@@ -1420,24 +2175,1374 @@ where
                 self.store(operand2, (i & 0xf0) | (a & 0x03))
             }
             Instruction::XRL(operand1, operand2) => {
-                let data = self.load(operand1)? ^ self.load(operand2)?;
+                let data = self.load_latch(operand1)? ^ self.load(operand2)?;
                 self.store(operand1, data)
             }
-            Instruction::LoadDptr(a) => {
-                self.data_pointer = a;
-                Ok(())
-            }
+            Instruction::LoadDptr(a) => self.store16(AddressingMode::Register(Register::DPTR), a),
         };
-        self.program_counter = next_program_counter;
+        if result.is_ok() {
+            self.program_counter = next_program_counter;
+        }
         self.flags
             .set(Flags::PARITY, self.accumulator.count_ones() & 1 == 1);
+        self.interrupt_inhibit = matches!(instruction, Instruction::RETI)
+            || self.writes.iter().any(|(address, _)| {
+                matches!(
+                    address,
+                    Address::SpecialFunctionRegister(0xA8)
+                        | Address::SpecialFunctionRegister(0xB8)
+                        | Address::Bit(0xA8..=0xAF)
+                        | Address::Bit(0xB8..=0xBF)
+                )
+            });
         result
     }
 
-    pub fn step(&mut self) -> Result<(), &'static str> {
-        let instruction = self.decode_next_instruction()?;
-        self.execute_instruction(instruction)?;
-        Rc::get_mut(&mut self.memory).unwrap().tick();
+    // execute one instruction (or enter an interrupt). after an error the pc still points at
+    // the instruction which failed, whether decoding or executing it did
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.step_detailed()?;
         Ok(())
     }
+
+    // step until a watchpoint is hit, at most the step limit instructions
+    pub fn run(&mut self) -> Result<StopReason, CpuError> {
+        for _ in 0..self.step_limit {
+            let record = self.step_detailed()?;
+            for (address, data) in record.writes {
+                // is_none_or would need rust 1.82
+                #[allow(clippy::unnecessary_map_or)]
+                let watched = self.watchpoints.iter().any(|watchpoint| {
+                    watchpoint.address == address && watchpoint.value.map_or(true, |v| v == data)
+                });
+                if watched {
+                    return Ok(StopReason::Watchpoint(address, data));
+                }
+            }
+        }
+        Ok(StopReason::StepLimit)
+    }
+
+    // step lazily, one instruction per next() as (address, instruction). the stream is
+    // unbounded and ends after the first error.
+    pub fn instructions(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(u16, Instruction), CpuError>> + '_ {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let result = self
+                .step_detailed()
+                .map(|record| (record.program_counter, record.instruction));
+            failed = result.is_err();
+            Some(result)
+        })
+    }
+
+    // step, reporting the instruction executed and what it changed. the order within a step
+    // is fixed: poll interrupts (at the instruction boundary), execute, then tick every
+    // peripheral once per machine cycle. an interrupt raised during the ticks is therefore
+    // taken at the start of the next step.
+    pub fn step_detailed(&mut self) -> Result<StepRecord, CpuError> {
+        let program_counter = self.program_counter;
+        let stack_pointer = self.stack_pointer;
+        let registers = self.core_registers();
+        let instruction = self.decode_next_instruction()?;
+        // interrupts are not fetched from code memory
+        if !matches!(instruction, Instruction::Interrupt(_, _)) {
+            self.executed_opcodes[self.opcode as usize] += 1;
+            if self.pc_coverage_enabled {
+                *self.pc_coverage.entry(program_counter).or_insert(0) += 1;
+            }
+        }
+        let length = self.decode_instruction_length(instruction)?;
+        let cycles = self.execute(instruction, length)?;
+        if self.trace.is_some() {
+            self.write_trace(program_counter, instruction);
+        }
+        self.check_stack(program_counter, instruction, stack_pointer);
+
+        let mut writes = std::mem::take(&mut self.writes);
+        for ((address, before), (_, after)) in registers.iter().zip(self.core_registers().iter()) {
+            if before != after {
+                writes.push((Address::SpecialFunctionRegister(*address), *after));
+            }
+        }
+        Ok(StepRecord {
+            program_counter,
+            instruction,
+            cycles,
+            writes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcs51::cfg::EdgeKind;
+    use crate::mcs51::memory::mock::{cpu_with_program, Access, MockMemory, RamMemory};
+
+    use proptest::prelude::*;
+
+    // accesses other than code fetches, in order
+    fn data_accesses(cpu: &CPU<MockMemory>) -> Vec<Access> {
+        cpu.memory
+            .accesses()
+            .iter()
+            .filter(|access| {
+                !matches!(
+                    access,
+                    Access::Read(Address::Code(_), _) | Access::Write(Address::Code(_), _)
+                )
+            })
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn read_modify_write_reads_latch_then_writes() {
+        // INC P1
+        let mut cpu = CPU::new(Rc::new(MockMemory::new(&[0x05, 0x90])));
+        let mem = cpu.memory_mut();
+        mem.write_memory(Address::SpecialFunctionRegister(0x90), 0x41)
+            .unwrap();
+        mem.clear_accesses();
+
+        cpu.step().unwrap();
+        assert_eq!(
+            data_accesses(&cpu),
+            vec![
+                Access::ReadLatch(Address::SpecialFunctionRegister(0x90), 0x41),
+                Access::Write(Address::SpecialFunctionRegister(0x90), 0x42),
+            ]
+        );
+    }
+
+    #[test]
+    fn mov_immediate_then_add() {
+        // MOV A, #0x12; ADD A, #0x34
+        let mut cpu = cpu_with_program(&[0x74, 0x12, 0x24, 0x34]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().accumulator, 0x46);
+        assert_eq!(cpu.state().program_counter, 4);
+    }
+
+    #[test]
+    fn mov_direct_to_indirect() {
+        // MOV R0, #0x40; MOV @R0, #0x5A; MOV A, 0x40
+        let mut cpu = cpu_with_program(&[0x78, 0x40, 0x76, 0x5A, 0xE5, 0x40]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x40)),
+            Ok(0x5A)
+        );
+        assert_eq!(cpu.state().accumulator, 0x5A);
+    }
+
+    // every opcode against boundary values of A, SP, carry and operand bytes may fail but must
+    // never panic
+    #[test]
+    fn no_opcode_panics_at_boundaries() {
+        use crate::mcs51::memory::{RAM, ROM};
+        use crate::mcs51::soc::p80c550::{create, ChipVariant};
+
+        let mut rom = ROM::blank(0x10000);
+        rom.set_writable(true);
+        let mut cpu = create(
+            Rc::new(rom),
+            Rc::new(RAM::create_with_size(0x10000)),
+            ChipVariant::P80C550,
+        );
+        let values = [0x00, 0x7F, 0x80, 0xFF];
+        let operands = [0x00, 0x7F, 0x80, 0xBF, 0xFF];
+        for opcode in 0..=0xFFu8 {
+            for &operand in operands.iter() {
+                for &accumulator in values.iter() {
+                    for &stack_pointer in [0x00, 0x07, 0xFF].iter() {
+                        for &carry in [false, true].iter() {
+                            let mem = cpu.memory_mut();
+                            for (address, byte) in [opcode, operand, operand].iter().enumerate() {
+                                mem.write_memory(Address::Code(address as u16), *byte)
+                                    .unwrap();
+                            }
+                            cpu.program_counter = 0;
+                            cpu.accumulator = accumulator;
+                            cpu.stack_pointer = stack_pointer;
+                            cpu.flags.set(Flags::CARRY, carry);
+                            let _ = cpu.step();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn balanced_lcall_ret_is_no_error() {
+        // LCALL 0x0010; SJMP $ ... 0x0010: RET
+        let mut program = vec![0x12, 0x00, 0x10, 0x80, 0xFE];
+        program.resize(0x10, 0x00);
+        program.push(0x22);
+        let mut cpu = cpu_with_program(&program);
+        cpu.memory_mut().set_strict(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.stack_pointer, 0x09);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0003);
+        assert_eq!(cpu.stack_pointer, 0x07);
+    }
+
+    #[test]
+    fn bare_ret_at_reset_sp() {
+        // RET with SP at 0x07 pops bank 0, an error in strict mode and a wrap otherwise
+        let mut cpu = cpu_with_program(&[0x22]);
+        cpu.memory_mut().set_strict(true);
+        assert_eq!(cpu.step(), Err(CpuError::StackUnderflow(0x07)));
+        assert_eq!(cpu.program_counter, 0);
+
+        let mut cpu = cpu_with_program(&[0x22]);
+        cpu.stack_pointer = 0x00;
+        cpu.step().unwrap();
+        assert_eq!(cpu.stack_pointer, 0xFE);
+    }
+
+    #[test]
+    fn div_by_zero_and_by_five() {
+        // MOV A, #0x37; MOV B, #0x00; DIV AB
+        let mut cpu = cpu_with_program(&[0x74, 0x37, 0x75, 0xF0, 0x00, 0x84]);
+        cpu.flags.insert(Flags::CARRY);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.accumulator, cpu.b), (0x37, 0x00));
+        assert!(cpu.flags.contains(Flags::OVERFLOW));
+        assert!(!cpu.flags.contains(Flags::CARRY));
+
+        // MOV A, #0x37; MOV B, #0x05; DIV AB, 55 / 5 = 11 remainder 0
+        let mut cpu = cpu_with_program(&[0x74, 0x37, 0x75, 0xF0, 0x05, 0x84]);
+        cpu.flags.insert(Flags::OVERFLOW | Flags::CARRY);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.accumulator, cpu.b), (0x0B, 0x00));
+        assert!(!cpu.flags.contains(Flags::OVERFLOW));
+        assert!(!cpu.flags.contains(Flags::CARRY));
+    }
+
+    #[test]
+    fn watchpoint_stops_on_matching_value_only() {
+        // MOV DPTR, #0x1000; MOV A, #0x53; MOVX @DPTR, A; INC A; MOVX @DPTR, A; INC A;
+        // MOVX @DPTR, A; INC A; MOVX @DPTR, A; SJMP $
+        let mut cpu = cpu_with_program(&[
+            0x90, 0x10, 0x00, 0x74, 0x53, 0xF0, 0x04, 0xF0, 0x04, 0xF0, 0x04, 0xF0, 0x80, 0xFE,
+        ]);
+        cpu.add_watchpoint(Watchpoint {
+            address: Address::ExternalData(0x1000),
+            value: Some(0x55),
+        });
+        assert_eq!(
+            cpu.run(),
+            Ok(StopReason::Watchpoint(Address::ExternalData(0x1000), 0x55))
+        );
+        assert_eq!(cpu.program_counter, 0x000A);
+    }
+
+    #[test]
+    fn stack_check_reports_push_without_pop() {
+        // LCALL 0x0010; SJMP $ ... 0x0010: PUSH ACC; RET
+        let mut program = vec![0x12, 0x00, 0x10, 0x80, 0xFE];
+        program.resize(0x10, 0x00);
+        program.extend_from_slice(&[0xC0, 0xE0, 0x22]);
+        let mut cpu = cpu_with_program(&program);
+        cpu.set_stack_check(true);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            cpu.stack_imbalances(),
+            &[StackImbalance {
+                call_site: 0x0000,
+                return_site: 0x0012,
+                expected: 0x09,
+                actual: 0x0A,
+            }]
+        );
+    }
+
+    #[test]
+    fn da_after_add() {
+        // MOV A, #0x99; ADD A, #0x01; DA A
+        let mut cpu = cpu_with_program(&[0x74, 0x99, 0x24, 0x01, 0xD4]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // 0x99 + 0x01 = 0x9A, 9 + 1 doesn't carry out of bit 3 so AC is clear, and C is clear
+        assert_eq!(cpu.accumulator, 0x9A);
+        assert!(!cpu.flags.contains(Flags::AUXILIARYCARRY));
+        assert!(!cpu.flags.contains(Flags::CARRY));
+        // low nibble 0xA > 9 adds 0x06 (0xA0), high nibble 0xA > 9 adds 0x60 (0x100): 99 + 1
+        // is 100 in bcd, A = 0x00 with carry set
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x00);
+        assert!(cpu.flags.contains(Flags::CARRY));
+    }
+
+    // A after a + data (or a - data with SUBB) with no carry in, and the C and OV flags
+    fn arithmetic(subtract: bool, a: u8, data: u8) -> (u8, bool, bool) {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.accumulator = a;
+        if subtract {
+            cpu.subtract(data, 0);
+        } else {
+            cpu.add(data, 0);
+        }
+        (
+            cpu.accumulator,
+            cpu.flags.contains(Flags::CARRY),
+            cpu.flags.contains(Flags::OVERFLOW),
+        )
+    }
+
+    #[test]
+    fn add_overflow() {
+        // 127 + 1 carries into bit 7 only
+        assert_eq!(arithmetic(false, 0x7F, 0x01), (0x80, false, true));
+        // -128 + -1 carries out of bit 7 only
+        assert_eq!(arithmetic(false, 0x80, 0xFF), (0x7F, true, true));
+        // 63 + 64 carries out of neither
+        assert_eq!(arithmetic(false, 0x3F, 0x40), (0x7F, false, false));
+    }
+
+    #[test]
+    fn subb_overflow() {
+        // -128 - 1 borrows into bit 7 only
+        assert_eq!(arithmetic(true, 0x80, 0x01), (0x7F, false, true));
+        // 127 - -1 is 128, out of signed range, and unsigned 0x7F < 0xFF borrows into bit 7
+        assert_eq!(arithmetic(true, 0x7F, 0xFF), (0x80, true, true));
+    }
+
+    #[test]
+    fn djnz_delay_loop_cycles() {
+        // MOV R7, #100; DJNZ R7, $; NOP
+        let mut cpu = cpu_with_program(&[0x7F, 100, 0xDF, 0xFE, 0x00]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles(), 1);
+        while cpu.program_counter != 0x0004 {
+            cpu.step().unwrap();
+        }
+        // MOV Rn, #data takes 1 cycle and the loop runs DJNZ 100 times at 2 cycles each:
+        // 1 + 100 * 2 = 201 machine cycles, 201 * 12 = 2412 oscillator clocks
+        assert_eq!(cpu.cycles(), 1 + 100 * 2);
+        assert_eq!(cpu.clocks(), 201 * 12);
+        assert_eq!(cpu.executed_opcodes()[0xDF], 100);
+    }
+
+    #[test]
+    fn decoded_lengths_match_opcode_lengths() {
+        for opcode in 0..=0xFFu8 {
+            let mut cpu = CPU::new(Rc::new(MockMemory::new(&[opcode, 0x11, 0x22, 0x33])));
+            let decoded = cpu.decode_opcode(0x0000);
+            if opcode == 0xA5 {
+                assert!(decoded.is_err());
+                continue;
+            }
+            let length = cpu.decode_instruction_length(decoded.unwrap()).unwrap();
+            assert_eq!(
+                length, OPCODE_LENGTHS[opcode as usize] as u16,
+                "opcode {:02x}",
+                opcode
+            );
+
+            // decoding reads the opcode and its operands, nothing past them
+            let fetched: Vec<u16> = cpu
+                .memory
+                .accesses()
+                .iter()
+                .filter_map(|access| match access {
+                    Access::Read(Address::Code(address), _) => Some(*address),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                fetched,
+                (0..length).collect::<Vec<u16>>(),
+                "opcode {:02x}",
+                opcode
+            );
+        }
+    }
+
+    proptest! {
+        // pushing bytes and popping them back in reverse restores every byte and SP
+        #[test]
+        fn push_pop_are_inverses(
+            values in prop::collection::vec(any::<u8>(), 1..16),
+            stack_pointer in 0x07u8..0x50,
+        ) {
+            // MOV A, #value; PUSH ACC for each value, then POP 0x70 + i in reverse
+            let mut program = Vec::new();
+            for value in values.iter() {
+                program.extend_from_slice(&[0x74, *value, 0xC0, 0xE0]);
+            }
+            for i in (0..values.len()).rev() {
+                program.extend_from_slice(&[0xD0, 0x70 + i as u8]);
+            }
+            let mut cpu = cpu_with_program(&program);
+            cpu.stack_pointer = stack_pointer;
+            for _ in 0..values.len() * 3 {
+                cpu.step().unwrap();
+            }
+            prop_assert_eq!(cpu.stack_pointer, stack_pointer);
+            for (i, value) in values.iter().enumerate() {
+                let address = Address::InternalData(0x70 + i as u8);
+                prop_assert_eq!(cpu.memory_mut().read_memory(address), Ok(*value));
+            }
+        }
+
+        // nested LCALLs push their return addresses low byte first and the RETs unwind them
+        // back to the caller with SP restored
+        #[test]
+        fn lcall_ret_are_inverses(depth in 1usize..8, stack_pointer in 0x07u8..0x60) {
+            // 0x0000: LCALL 0x0100; SJMP $, function k at 0x100 * k: LCALL the next; RET
+            let mut program = vec![0x12, 0x01, 0x00, 0x80, 0xFE];
+            for k in 1..=depth {
+                program.resize(0x100 * k, 0x00);
+                if k < depth {
+                    program.extend_from_slice(&[0x12, k as u8 + 1, 0x00]);
+                }
+                program.push(0x22);
+            }
+            let mut cpu = cpu_with_program(&program);
+            cpu.stack_pointer = stack_pointer;
+            for _ in 0..depth {
+                cpu.step().unwrap();
+            }
+            prop_assert_eq!(cpu.stack_pointer, stack_pointer + 2 * depth as u8);
+            let mem = cpu.memory_mut();
+            let low = mem.read_memory(Address::InternalData(stack_pointer + 1));
+            let high = mem.read_memory(Address::InternalData(stack_pointer + 2));
+            prop_assert_eq!((low, high), (Ok(0x03), Ok(0x00)));
+            for _ in 0..depth {
+                cpu.step().unwrap();
+            }
+            prop_assert_eq!(cpu.program_counter, 0x0003);
+            prop_assert_eq!(cpu.stack_pointer, stack_pointer);
+        }
+    }
+
+    #[test]
+    fn cjne_indirect_immediate() {
+        for &(byte, program_counter, carry) in
+            [(0x60, 9, false), (0x40, 9, true), (0x50, 7, false)].iter()
+        {
+            // MOV R0, #0x40; MOV @R0, #byte; CJNE @R0, #0x50, +2
+            let mut cpu = cpu_with_program(&[0x78, 0x40, 0x76, byte, 0xB6, 0x50, 0x02]);
+            for _ in 0..3 {
+                cpu.step().unwrap();
+            }
+            assert_eq!(cpu.program_counter, program_counter, "byte {:02x}", byte);
+            assert_eq!(cpu.flags.contains(Flags::CARRY), carry, "byte {:02x}", byte);
+        }
+    }
+
+    #[test]
+    fn mov_psw_sets_carry_and_bank() {
+        // MOV 0x08, #0x5A; MOV PSW, #0x88 (CY, RS0); JC +1; NOP; MOV A, R0
+        let mut cpu =
+            cpu_with_program(&[0x75, 0x08, 0x5A, 0x75, 0xD0, 0x88, 0x40, 0x01, 0x00, 0xE8]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // the jump skipped the NOP and R0 of bank 1 is iram 0x08
+        assert_eq!(cpu.program_counter, 10);
+        assert_eq!(cpu.bank(), 1);
+        assert_eq!(cpu.accumulator, 0x5A);
+    }
+
+    #[test]
+    fn parity_readable_as_psw0() {
+        // MOV A, #0x07; JB P, +1; NOP; MOV A, #0x03; JNB P, +1; NOP
+        let mut cpu = cpu_with_program(&[
+            0x74, 0x07, 0x20, 0xD0, 0x01, 0x00, 0x74, 0x03, 0x30, 0xD0, 0x01, 0x00,
+        ]);
+        // three bits set is odd parity, JB jumps over the NOP
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 6);
+        // two bits set is even parity, JNB jumps over the NOP
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 12);
+    }
+
+    #[test]
+    fn push_aliases_register_bank() {
+        // MOV SP, #0x00; MOV B, #0x77; PUSH B; MOV A, R1
+        let mut cpu = cpu_with_program(&[0x75, 0x81, 0x00, 0x75, 0xF0, 0x77, 0xC0, 0xF0, 0xE9]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // the push landed in iram 0x01, which is R1 of bank 0
+        assert_eq!(cpu.stack_pointer, 0x01);
+        assert_eq!(cpu.accumulator, 0x77);
+    }
+
+    // ram memory with a receive buffer whose read clears RI, standing in for a side-effecting
+    // SFR read
+    struct SbufMemory {
+        ram: RamMemory,
+        sbuf: u8,
+        scon: u8,
+        sbuf_reads: u32,
+    }
+
+    impl Memory for SbufMemory {
+        fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+            match address {
+                Address::SpecialFunctionRegister(0x98) => Ok(self.scon),
+                Address::SpecialFunctionRegister(0x99) => {
+                    self.sbuf_reads += 1;
+                    self.scon &= !0x01;
+                    Ok(self.sbuf)
+                }
+                _ => self.ram.read_memory(address),
+            }
+        }
+
+        fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+            self.ram.write_memory(address, data)
+        }
+
+        fn tick(&mut self) {}
+    }
+
+    impl InterruptSource for SbufMemory {
+        fn peek_vector(&mut self) -> Option<(u16, u8)> {
+            None
+        }
+
+        fn pop_vector(&mut self) {}
+    }
+
+    #[test]
+    fn mov_direct_direct_reads_source_once() {
+        // MOV P1, SBUF
+        let mut cpu = CPU::new(Rc::new(SbufMemory {
+            ram: RamMemory::new(&[0x85, 0x99, 0x90]),
+            sbuf: 0x3C,
+            scon: 0x01,
+            sbuf_reads: 0,
+        }));
+        cpu.step().unwrap();
+        let mem = cpu.memory_mut();
+        assert_eq!(mem.sbuf_reads, 1);
+        assert_eq!(mem.scon & 0x01, 0);
+        assert_eq!(
+            mem.read_memory(Address::SpecialFunctionRegister(0x90)),
+            Ok(0x3C)
+        );
+    }
+
+    #[test]
+    fn movx_reads_device_once() {
+        // MOV DPTR, #0x1234; MOVX A, @DPTR
+        let mut cpu = CPU::new(Rc::new(MockMemory::new(&[0x90, 0x12, 0x34, 0xE0])));
+        cpu.step().unwrap();
+        cpu.memory_mut().clear_accesses();
+        cpu.step().unwrap();
+        assert_eq!(
+            data_accesses(&cpu),
+            vec![Access::Read(Address::ExternalData(0x1234), 0x00)]
+        );
+    }
+
+    #[test]
+    fn execute_instruction_without_fetch() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.accumulator = 0xF0;
+        cpu.execute_instruction(Instruction::ADD(AddressingMode::Immediate(0x20)))
+            .unwrap();
+        assert_eq!(cpu.accumulator, 0x10);
+        assert!(cpu.flags.contains(Flags::CARRY));
+        assert_eq!(cpu.program_counter, 2);
+
+        // 0x10 - 0x05 - 1, the carry left by the ADD borrows
+        cpu.execute_instruction(Instruction::SUBB(AddressingMode::Immediate(0x05)))
+            .unwrap();
+        assert_eq!(cpu.accumulator, 0x0A);
+        assert!(!cpu.flags.contains(Flags::CARRY));
+        assert!(cpu.flags.contains(Flags::AUXILIARYCARRY));
+        assert_eq!(cpu.program_counter, 4);
+
+        // 0x0A * 0x30 = 0x01E0, B holds the high byte and OV is set
+        cpu.b = 0x30;
+        cpu.execute_instruction(Instruction::MUL).unwrap();
+        assert_eq!((cpu.accumulator, cpu.b), (0xE0, 0x01));
+        assert!(cpu.flags.contains(Flags::OVERFLOW));
+        assert_eq!(cpu.program_counter, 5);
+        // nothing ran on the clock
+        assert_eq!(cpu.cycles(), 0);
+    }
+
+    #[test]
+    fn carry_logic_orl_and_anl_not() {
+        for &carry in [false, true].iter() {
+            for &bit in [false, true].iter() {
+                // ORL C, 0x00; then ANL C, /0x00 on the same inputs
+                for &(opcode, result) in [(0x72, carry | bit), (0xB0, carry & !bit)].iter() {
+                    let mut cpu = cpu_with_program(&[opcode, 0x00]);
+                    cpu.flags.set(Flags::CARRY, carry);
+                    cpu.memory_mut()
+                        .write_memory(Address::InternalData(0x20), bit as u8)
+                        .unwrap();
+                    cpu.step().unwrap();
+                    assert_eq!(
+                        cpu.flags.carry(),
+                        result as u8,
+                        "{:02x} {} {}",
+                        opcode,
+                        carry,
+                        bit
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mul_div_take_four_cycles() {
+        // MUL AB; ADD A, #0x01; DIV AB
+        let mut cpu = cpu_with_program(&[0xA4, 0x24, 0x01, 0x84]);
+        cpu.b = 1;
+        assert_eq!(cpu.step_detailed().unwrap().cycles, 4);
+        assert_eq!(cpu.step_detailed().unwrap().cycles, 1);
+        assert_eq!(cpu.step_detailed().unwrap().cycles, 4);
+        assert_eq!(cpu.cycles(), 9);
+    }
+
+    #[test]
+    fn indirect_target_is_absolute_in_any_bank() {
+        // MOV PSW, #0x08; MOV R0, #0x30; MOV @R0, #0x77
+        let mut cpu = cpu_with_program(&[0x75, 0xD0, 0x08, 0x78, 0x30, 0x76, 0x77]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let mem = cpu.memory_mut();
+        // the pointer is bank 1's R0, the target is not offset by the bank
+        assert_eq!(mem.read_memory(Address::InternalData(0x08)), Ok(0x30));
+        assert_eq!(mem.read_memory(Address::InternalData(0x30)), Ok(0x77));
+        assert_eq!(mem.read_memory(Address::InternalData(0x38)), Ok(0x00));
+        assert_eq!(mem.read_memory(Address::InternalData(0x00)), Ok(0x00));
+    }
+
+    #[test]
+    fn bit_addresses_map_into_0x20_to_0x2f() {
+        for &(bit, byte, mask) in [
+            (0x00, 0x20, 0x01),
+            (0x07, 0x20, 0x80),
+            (0x08, 0x21, 0x01),
+            (0x7F, 0x2F, 0x80),
+        ]
+        .iter()
+        {
+            // SETB bit; MOV C, bit; CLR bit
+            let mut cpu = cpu_with_program(&[0xD2, bit, 0xA2, bit, 0xC2, bit]);
+            cpu.step().unwrap();
+            for address in 0x1F..=0x30 {
+                let expected = if address == byte { mask } else { 0x00 };
+                assert_eq!(
+                    cpu.memory_mut().read_memory(Address::InternalData(address)),
+                    Ok(expected),
+                    "bit {:02x} byte {:02x}",
+                    bit,
+                    address
+                );
+            }
+            cpu.step().unwrap();
+            assert!(cpu.flags.contains(Flags::CARRY));
+            cpu.step().unwrap();
+            assert_eq!(
+                cpu.memory_mut().read_memory(Address::InternalData(byte)),
+                Ok(0x00)
+            );
+        }
+    }
+
+    #[test]
+    fn jz_jnz_see_the_previous_result() {
+        let cases: [(&[u8], bool); 4] = [
+            // MOV A, #0x5A; ANL A, #0x00
+            (&[0x74, 0x5A, 0x54, 0x00], true),
+            // MOV A, #0x5A; XRL A, #0x0F
+            (&[0x74, 0x5A, 0x64, 0x0F], false),
+            // MOV A, #0xF0; ADD A, #0x10
+            (&[0x74, 0xF0, 0x24, 0x10], true),
+            // MOV A, #0x50; SWAP A
+            (&[0x74, 0x50, 0xC4, 0x00], false),
+        ];
+        for &(setup, zero) in cases.iter() {
+            // JZ +1 and JNZ +1, each jumping over a NOP when taken
+            for &(opcode, taken) in [(0x60, zero), (0x70, !zero)].iter() {
+                let mut program = setup.to_vec();
+                program.extend_from_slice(&[opcode, 0x01, 0x00]);
+                let mut cpu = cpu_with_program(&program);
+                while cpu.program_counter < 4 {
+                    cpu.step().unwrap();
+                }
+                cpu.step().unwrap();
+                let target = if taken { 7 } else { 6 };
+                assert_eq!(cpu.program_counter, target, "{:02x?}", program);
+            }
+        }
+    }
+
+    #[test]
+    fn pop_acc_restores_parity() {
+        // MOV A, #0x07; PUSH ACC; CLR A; POP ACC
+        let mut cpu = cpu_with_program(&[0x74, 0x07, 0xC0, 0xE0, 0xE4, 0xD0, 0xE0]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.accumulator, 0x00);
+        assert!(!cpu.flags.contains(Flags::PARITY));
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x07);
+        assert!(cpu.flags.contains(Flags::PARITY));
+    }
+
+    #[test]
+    fn subb_auxiliary_borrow() {
+        // (A, operand, borrow in, result, AC)
+        for &(a, data, borrow, result, auxiliary) in [
+            (0x10, 0x01, false, 0x0F, true),
+            (0x1F, 0x01, false, 0x1E, false),
+            (0x10, 0x00, true, 0x0F, true),
+            (0x11, 0x00, true, 0x10, false),
+            (0x1F, 0x0F, true, 0x0F, true),
+        ]
+        .iter()
+        {
+            // SUBB A, #data
+            let mut cpu = cpu_with_program(&[0x94, data]);
+            cpu.accumulator = a;
+            cpu.flags.set(Flags::CARRY, borrow);
+            cpu.step().unwrap();
+            assert_eq!(cpu.accumulator, result);
+            assert_eq!(
+                cpu.flags.contains(Flags::AUXILIARYCARRY),
+                auxiliary,
+                "{:02x} - {:02x} - {}",
+                a,
+                data,
+                borrow
+            );
+        }
+    }
+
+    #[test]
+    fn movx_r1_uses_p2_as_high_byte() {
+        // MOV P2, #0x20; MOV R1, #0x80; MOV A, #0x5A; MOVX @R1, A; CLR A; MOVX A, @R1
+        let mut cpu =
+            cpu_with_program(&[0x75, 0xA0, 0x20, 0x79, 0x80, 0x74, 0x5A, 0xF3, 0xE4, 0xE3]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        let mem = cpu.memory_mut();
+        assert_eq!(mem.read_memory(Address::ExternalData(0x2080)), Ok(0x5A));
+        assert_eq!(mem.read_memory(Address::ExternalData(0x0080)), Ok(0x00));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x5A);
+    }
+
+    #[test]
+    fn bit_and_byte_views_alias() {
+        // SETB 0x05; MOV A, 0x20; MOV 0x20, #0xFF; JB 0x03, +1; NOP
+        let mut cpu = cpu_with_program(&[
+            0xD2, 0x05, 0xE5, 0x20, 0x75, 0x20, 0xFF, 0x20, 0x03, 0x01, 0x00,
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x20);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 11);
+    }
+
+    #[test]
+    fn setb_clr_carry_and_bit_are_separate() {
+        // SETB C; SETB 0x00; CLR C; CLR 0x00
+        let mut cpu = cpu_with_program(&[0xD3, 0xD2, 0x00, 0xC3, 0xC2, 0x00]);
+        // (carry, iram 0x20) after each instruction
+        for &(carry, byte) in [(true, 0x00), (true, 0x01), (false, 0x01), (false, 0x00)].iter() {
+            cpu.step().unwrap();
+            assert_eq!(cpu.flags.contains(Flags::CARRY), carry);
+            assert_eq!(
+                cpu.memory_mut().read_memory(Address::InternalData(0x20)),
+                Ok(byte)
+            );
+        }
+    }
+
+    #[test]
+    fn step_record_for_mov_immediate() {
+        // MOV A, #0x42; MOV 0x30, A
+        let mut cpu = cpu_with_program(&[0x74, 0x42, 0xF5, 0x30]);
+        let record = cpu.step_detailed().unwrap();
+        assert_eq!(record.program_counter, 0);
+        assert!(matches!(
+            record.instruction,
+            Instruction::MOV(
+                AddressingMode::Register(Register::A),
+                AddressingMode::Immediate(0x42)
+            )
+        ));
+        assert_eq!(record.cycles, 1);
+        assert_eq!(
+            record.writes,
+            vec![(Address::SpecialFunctionRegister(0xE0), 0x42)]
+        );
+
+        let record = cpu.step_detailed().unwrap();
+        assert_eq!(record.program_counter, 2);
+        assert_eq!(record.writes, vec![(Address::InternalData(0x30), 0x42)]);
+    }
+
+    #[test]
+    fn disassemble_range_advances_by_length() {
+        // NOP; MOV A, #0x12; LJMP 0x1234; MOV 0x30, 0x31; RET
+        let program = [0x00, 0x74, 0x12, 0x02, 0x12, 0x34, 0x85, 0x31, 0x30, 0x22];
+        let mut cpu = cpu_with_program(&program);
+        let listing = cpu.disassemble_range(0, 5);
+        let addresses: Vec<u16> = listing.iter().map(|(address, _, _)| *address).collect();
+        assert_eq!(addresses, [0, 1, 3, 6, 9]);
+        let bytes: Vec<u8> = listing
+            .iter()
+            .flat_map(|(_, _, bytes)| bytes.iter().copied())
+            .collect();
+        assert_eq!(bytes, program);
+        assert!(matches!(listing[2].1, Instruction::LJMP(0x1234)));
+        assert!(matches!(listing[4].1, Instruction::RET));
+        // decoding doesn't run anything
+        assert_eq!(cpu.program_counter, 0);
+    }
+
+    #[test]
+    fn clocks_scale_with_clocks_per_cycle() {
+        // NOP; LJMP 0x0000
+        let program = [0x00, 0x02, 0x00, 0x00];
+        let mut classic = cpu_with_program(&program);
+        let mut x2 = cpu_with_program(&program);
+        x2.set_clocks_per_cycle(6);
+        for _ in 0..100 {
+            classic.step().unwrap();
+            x2.step().unwrap();
+        }
+        assert_eq!(classic.cycles(), 150);
+        assert_eq!(x2.cycles(), 150);
+        assert_eq!(classic.clocks(), 12 * 150);
+        assert_eq!(x2.clocks(), classic.clocks() / 2);
+    }
+
+    #[test]
+    fn executed_opcodes_counts_each_opcode() {
+        // MOV A, #0x03; loop: DEC A; JNZ loop; CPL C; NOP
+        let mut cpu = cpu_with_program(&[0x74, 0x03, 0x14, 0x70, 0xFD, 0xB3, 0x00]);
+        for _ in 0..9 {
+            cpu.step().unwrap();
+        }
+        let executed = cpu.executed_opcodes();
+        assert_eq!(executed[0x74], 1);
+        assert_eq!(executed[0x14], 3);
+        assert_eq!(executed[0x70], 3);
+        assert_eq!(executed[0xB3], 1);
+        assert_eq!(executed[0x00], 1);
+        assert_eq!(executed.iter().sum::<u64>(), 9);
+
+        // counting doesn't fetch the opcode a second time
+        let mut cpu = CPU::new(Rc::new(MockMemory::new(&[0x74, 0x12])));
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.memory.accesses(),
+            &[
+                Access::Read(Address::Code(0x0000), 0x74),
+                Access::Read(Address::Code(0x0001), 0x12)
+            ]
+        );
+    }
+
+    #[test]
+    fn load16_reads_dptr() {
+        // MOV DPTR, #0x12FF; INC DPTR
+        let mut cpu = cpu_with_program(&[0x90, 0x12, 0xFF, 0xA3]);
+        let dptr = AddressingMode::Register(Register::DPTR);
+        cpu.step().unwrap();
+        assert_eq!(cpu.load16(dptr), Ok(0x12FF));
+        cpu.step().unwrap();
+        assert_eq!(cpu.load16(dptr), Ok(0x1300));
+        cpu.store16(dptr, 0xABCD).unwrap();
+        assert_eq!(cpu.state().data_pointer, 0xABCD);
+        assert_eq!(
+            cpu.load16(AddressingMode::Register(Register::A)),
+            Err(CpuError::UnsupportedAddressingMode(
+                AddressingMode::Register(Register::A)
+            ))
+        );
+    }
+
+    #[test]
+    fn unsupported_operands_are_typed_errors() {
+        let mut cpu = cpu_with_program(&[]);
+        assert_eq!(
+            cpu.load(AddressingMode::Register(Register::PC)),
+            Err(CpuError::UnsupportedRegister(Register::PC))
+        );
+        assert_eq!(
+            cpu.load(AddressingMode::Indirect(Register::R2)),
+            Err(CpuError::UnsupportedRegister(Register::R2))
+        );
+        assert_eq!(
+            cpu.store(AddressingMode::Register(Register::DPTR), 0x00),
+            Err(CpuError::UnsupportedRegister(Register::DPTR))
+        );
+        assert_eq!(
+            cpu.store(AddressingMode::Immediate(0x12), 0x00),
+            Err(CpuError::UnsupportedAddressingMode(
+                AddressingMode::Immediate(0x12)
+            ))
+        );
+        // and the same through an instruction
+        assert_eq!(
+            cpu.execute_instruction(Instruction::MOV(
+                AddressingMode::Register(Register::A),
+                AddressingMode::Register(Register::PC)
+            )),
+            Err(CpuError::UnsupportedRegister(Register::PC))
+        );
+    }
+
+    #[test]
+    fn dptr_byte_order() {
+        // MOV DPTR, #0x1234; MOV A, DPL; MOV B, DPH; MOV DPL, #0xCD; MOV DPH, #0xAB
+        let mut cpu = cpu_with_program(&[
+            0x90, 0x12, 0x34, 0xE5, 0x82, 0x85, 0x83, 0xF0, 0x75, 0x82, 0xCD, 0x75, 0x83, 0xAB,
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.state().data_pointer, 0x1234);
+        assert_eq!((cpu.accumulator, cpu.b), (0x34, 0x12));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().data_pointer, 0xABCD);
+    }
+
+    #[test]
+    fn custom_opcode_handler_runs() {
+        // 0xA5 is undefined on the 8051
+        assert!(cpu_with_program(&[0xA5, 0x07]).step().is_err());
+
+        // 0xA5 #data: A += data, and a copy of the result at iram 0x30
+        let mut cpu = cpu_with_program(&[0xA5, 0x07, 0x00]);
+        cpu.set_custom_opcode(
+            0xA5,
+            Box::new(|context: &mut CpuContext| {
+                let data = context.read_memory(Address::Code(context.program_counter + 1))?;
+                context.accumulator = context.accumulator.wrapping_add(data);
+                context.write_memory(Address::InternalData(0x30), context.accumulator)?;
+                context.program_counter += 2;
+                Ok(3)
+            }),
+        );
+        cpu.accumulator = 0x10;
+        let record = cpu.step_detailed().unwrap();
+        assert!(matches!(record.instruction, Instruction::Custom(0xA5)));
+        assert_eq!(record.cycles, 3);
+        assert_eq!(cpu.accumulator, 0x17);
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x30)),
+            Ok(0x17)
+        );
+    }
+
+    #[test]
+    fn core_registers_stay_in_the_cpu() {
+        // MOV P1, #0x5A; MOV A, P1; MOV ACC, #0x33; MOV B, A
+        let program = [0x75, 0x90, 0x5A, 0xE5, 0x90, 0x75, 0xE0, 0x33, 0xF5, 0xF0];
+        let mut cpu = CPU::new(Rc::new(MockMemory::new(&program)));
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // P1 reaches the memory, ACC and B don't
+        assert_eq!(
+            data_accesses(&cpu),
+            vec![
+                Access::Write(Address::SpecialFunctionRegister(0x90), 0x5A),
+                Access::Read(Address::SpecialFunctionRegister(0x90), 0x5A),
+            ]
+        );
+        assert_eq!((cpu.accumulator, cpu.b), (0x33, 0x33));
+    }
+
+    #[test]
+    fn run_stops_at_step_limit() {
+        // SJMP $
+        let mut cpu = cpu_with_program(&[0x80, 0xFE]);
+        cpu.set_step_limit(1000);
+        assert_eq!(cpu.run(), Ok(StopReason::StepLimit));
+        // each SJMP takes two machine cycles
+        assert_eq!(cpu.cycles(), 2000);
+        assert_eq!(cpu.program_counter, 0);
+    }
+
+    #[test]
+    fn set_bank_moves_registers_and_psw() {
+        // MOV R0, #0x5A
+        let mut cpu = cpu_with_program(&[0x78, 0x5A]);
+        cpu.set_bank(2);
+        assert_eq!(cpu.bank(), 2);
+        assert!(cpu.flags.contains(Flags::BANKSELECT1));
+        assert!(!cpu.flags.contains(Flags::BANKSELECT0));
+        cpu.step().unwrap();
+        let mem = cpu.memory_mut();
+        assert_eq!(mem.read_memory(Address::InternalData(0x10)), Ok(0x5A));
+        assert_eq!(mem.read_memory(Address::InternalData(0x00)), Ok(0x00));
+
+        cpu.set_bank(1);
+        assert_eq!(cpu.state().psw & 0x18, 0x08);
+        assert_eq!(cpu.bank(), 1);
+    }
+
+    #[test]
+    fn trace_file_has_a_line_per_instruction() {
+        // MOV A, #0x12; MOV DPTR, #0x1234; PUSH ACC
+        let mut cpu = cpu_with_program(&[0x74, 0x12, 0x90, 0x12, 0x34, 0xC0, 0xE0]);
+        let path = std::env::temp_dir().join("mcs51_trace_lines.txt");
+        cpu.trace_to(&path).unwrap();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        cpu.finish().unwrap();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines.iter() {
+            let (registers, _) = line.split_once(" ; ").unwrap();
+            assert_eq!(registers.split(' ').count(), 6, "{}", line);
+        }
+        assert_eq!(
+            lines[0],
+            "0000 7412 A=12 PSW=00 SP=07 DPTR=0000 ; MOV A, #0x12"
+        );
+        assert_eq!(lines[2], "0005 c0e0 A=12 PSW=00 SP=08 DPTR=1234 ; PUSH ACC");
+    }
+
+    // accepts every write, fails to flush
+    struct UnflushableWriter;
+
+    impl Write for UnflushableWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn finish_returns_trace_flush_error() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.set_trace(Some(Box::new(UnflushableWriter)));
+        cpu.step().unwrap();
+        let error = cpu.finish().unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    // fails every write
+    struct FullWriter;
+
+    impl Write for FullWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_trace_write_stops_tracing_and_is_reported() {
+        // NOP; NOP
+        let mut cpu = cpu_with_program(&[0x00, 0x00]);
+        cpu.set_trace(Some(Box::new(FullWriter)));
+        cpu.step().unwrap();
+        // the cpu keeps running without the trace
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 2);
+        let error = cpu.finish().unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert!(cpu.finish().is_ok());
+    }
+
+    #[test]
+    fn pc_stays_at_faulting_instruction() {
+        // NOP; undefined 0xA5
+        let mut cpu = cpu_with_program(&[0x00, 0xA5]);
+        cpu.step().unwrap();
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.program_counter, 1);
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.program_counter, 1);
+
+        // a handler which advances the pc and then fails
+        let mut cpu = cpu_with_program(&[0x00, 0xA5]);
+        cpu.set_custom_opcode(
+            0xA5,
+            Box::new(|context: &mut CpuContext| {
+                context.program_counter += 1;
+                Err(CpuError::Fault("custom fault"))
+            }),
+        );
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(CpuError::Fault("custom fault")));
+        assert_eq!(cpu.program_counter, 1);
+    }
+
+    #[test]
+    fn disassemble_image_listing() {
+        // LCALL _func; SJMP $; undefined 0xA5; _func: RET; LJMP cut off by the end
+        let mut cpu = cpu_with_program(&[0x12, 0x00, 0x06, 0x80, 0xFE, 0xA5, 0x22, 0x02, 0x00]);
+        let mut symbols = SymbolTable::default();
+        symbols.insert_code(0x0000, "_start");
+        symbols.insert_code(0x0006, "_func");
+        cpu.set_symbols(symbols);
+        assert_eq!(
+            cpu.disassemble_image(0x0000, 0x0008),
+            "_start:\n\
+             0000: 12 00 06  LCALL _func\n\
+             0003: 80 FE     SJMP 0x0003\n\
+             0005: A5        .db 0xa5\n\
+             _func:\n\
+             0006: 22        RET\n\
+             0007: 02        .db 0x02\n"
+        );
+    }
+
+    #[test]
+    fn pc_coverage_counts_taken_path_only() {
+        // CLR A; JNZ skip; INC A; SJMP $; skip: DEC A
+        let mut cpu = cpu_with_program(&[0xE4, 0x70, 0x03, 0x04, 0x80, 0xFE, 0x14]);
+        cpu.step().unwrap();
+        assert!(cpu.pc_coverage().is_empty());
+
+        cpu.set_pc_coverage(true);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        let coverage = cpu.pc_coverage();
+        assert_eq!(coverage.get(&0x0000), None);
+        assert_eq!(coverage.get(&0x0001), Some(&1));
+        assert_eq!(coverage.get(&0x0003), Some(&1));
+        assert_eq!(coverage.get(&0x0004), Some(&3));
+        // the branch was never taken
+        assert_eq!(coverage.get(&0x0006), None);
+    }
+
+    #[test]
+    fn cfg_has_both_branch_successors() {
+        // JZ 0x0005; LCALL 0x0008; SJMP $; NOP; JMP @A+DPTR
+        let mut cpu = cpu_with_program(&[0x60, 0x03, 0x12, 0x00, 0x08, 0x80, 0xFE, 0x00, 0x73]);
+        let cfg = cpu.build_cfg(0x0000);
+        for edge in [
+            (0x0000, 0x0005, EdgeKind::Branch),
+            (0x0000, 0x0002, EdgeKind::Sequential),
+            (0x0002, 0x0008, EdgeKind::Call),
+            (0x0002, 0x0005, EdgeKind::Sequential),
+            (0x0005, 0x0005, EdgeKind::Branch),
+        ]
+        .iter()
+        {
+            assert!(cfg.edges.contains(edge), "{:?}", edge);
+        }
+        assert_eq!(cfg.edges.len(), 5);
+        assert_eq!(cfg.call_targets(), [0x0008]);
+        assert_eq!(cfg.computed_jumps, [0x0008]);
+        // the NOP is never reached
+        let reached: Vec<u16> = cfg.instructions.keys().copied().collect();
+        assert_eq!(reached, [0x0000, 0x0002, 0x0005, 0x0008]);
+    }
+
+    #[test]
+    fn execute_hand_built_instructions() {
+        let mut cpu = cpu_with_program(&[]);
+        let cycles = cpu
+            .execute(
+                Instruction::MOV(
+                    AddressingMode::Register(Register::A),
+                    AddressingMode::Immediate(0x12),
+                ),
+                2,
+            )
+            .unwrap();
+        assert_eq!(cycles, 1);
+        assert_eq!((cpu.accumulator, cpu.program_counter), (0x12, 0x0002));
+
+        // the return address is past the given length
+        assert_eq!(cpu.execute(Instruction::LCALL(0x0100), 3), Ok(2));
+        assert_eq!(cpu.program_counter, 0x0100);
+        assert_eq!(cpu.stack_pointer, 0x09);
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x08)),
+            Ok(0x05)
+        );
+
+        // relative branches are taken from the end of the instruction
+        assert_eq!(cpu.execute(Instruction::SJMP(-4), 2), Ok(2));
+        assert_eq!(cpu.program_counter, 0x00FE);
+        assert_eq!(cpu.execute(Instruction::RET, 1), Ok(2));
+        assert_eq!(cpu.program_counter, 0x0005);
+        assert_eq!(cpu.cycles(), 7);
+    }
+
+    #[test]
+    fn carry_instructions_leave_other_flags() {
+        // MOV A, #0x78; ADD A, #0x08; SETB C; CLR C; CPL C; CPL C
+        let mut cpu = cpu_with_program(&[0x74, 0x78, 0x24, 0x08, 0xD3, 0xC3, 0xB3, 0xB3]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x80);
+        assert!(cpu.flags.contains(Flags::OVERFLOW | Flags::AUXILIARYCARRY));
+        assert!(!cpu.flags.contains(Flags::CARRY));
+        // carry after each of SETB C, CLR C, CPL C, CPL C
+        for &carry in [true, false, true, false].iter() {
+            cpu.step().unwrap();
+            assert_eq!(cpu.flags.contains(Flags::CARRY), carry);
+            assert!(cpu.flags.contains(Flags::OVERFLOW | Flags::AUXILIARYCARRY));
+        }
+    }
+
+    #[test]
+    fn flat_addresses_round_trip() {
+        let addresses = [
+            (Address::Code(0x0000), 0x00_0000),
+            (Address::Code(0xFFFF), 0x00_FFFF),
+            (Address::ExternalData(0x1234), 0x01_1234),
+            (Address::InternalData(0x7F), 0x02_007F),
+            (Address::InternalData(0xFF), 0x02_00FF),
+            (Address::SpecialFunctionRegister(0x90), 0x03_0090),
+            (Address::Bit(0x00), 0x04_0000),
+            (Address::Bit(0xD7), 0x04_00D7),
+        ];
+        for &(address, flat) in addresses.iter() {
+            assert_eq!(address.to_flat(), flat);
+            assert_eq!(Address::from_flat(flat), Some(address));
+        }
+        // offsets past the end of a space and unknown spaces
+        for &flat in [0x02_0100, 0x03_0100, 0x04_0100, 0x05_0000, 0x1_0000_0000].iter() {
+            assert_eq!(Address::from_flat(flat), None, "{:x}", flat);
+        }
+    }
+
+    #[test]
+    fn instructions_iterator_yields_each_step() {
+        // MOV A, #0x01; INC A; SJMP $
+        let mut cpu = cpu_with_program(&[0x74, 0x01, 0x04, 0x80, 0xFE]);
+        let executed: Vec<(u16, Instruction)> = cpu
+            .instructions()
+            .take(4)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let addresses: Vec<u16> = executed.iter().map(|(address, _)| *address).collect();
+        assert_eq!(addresses, [0x0000, 0x0002, 0x0003, 0x0003]);
+        assert!(matches!(executed[1].1, Instruction::INC(_)));
+        assert!(matches!(executed[3].1, Instruction::SJMP(-2)));
+        assert_eq!(cpu.accumulator, 0x02);
+
+        // the stream ends after an error
+        let mut cpu = cpu_with_program(&[0x00, 0xA5, 0x00]);
+        let results: Vec<_> = cpu.instructions().take(5).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn effective_address_previews_operands() {
+        // MOV DPTR, #0x1234; MOV PSW, #0x08; MOV R1, #0x40; MOV R0, #0x80; MOV P2, #0x20
+        let mut cpu = cpu_with_program(&[
+            0x90, 0x12, 0x34, 0x75, 0xD0, 0x08, 0x79, 0x40, 0x78, 0x80, 0x75, 0xA0, 0x20,
+        ]);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        for &(mode, address) in [
+            (
+                AddressingMode::IndirectExternal(Register::DPTR),
+                Some(Address::ExternalData(0x1234)),
+            ),
+            (AddressingMode::Immediate(0x12), None),
+            (
+                AddressingMode::Indirect(Register::R1),
+                Some(Address::InternalData(0x40)),
+            ),
+            (
+                AddressingMode::Register(Register::R1),
+                Some(Address::InternalData(0x09)),
+            ),
+            (
+                AddressingMode::IndirectExternal(Register::R0),
+                Some(Address::ExternalData(0x2080)),
+            ),
+            (AddressingMode::Indirect(Register::R2), None),
+            (AddressingMode::IndirectExternal(Register::R7), None),
+        ]
+        .iter()
+        {
+            assert_eq!(cpu.effective_address(mode), address, "{:?}", mode);
+        }
+        // previewing doesn't step
+        assert_eq!(cpu.program_counter, 13);
+    }
+
+    #[test]
+    fn movc_changes_only_parity() {
+        // MOV A, #0x7F; ADD A, #0x01; SETB C; MOV DPTR, #0x0000; MOVC A, @A+DPTR
+        let mut program = vec![0x74, 0x7F, 0x24, 0x01, 0xD3, 0x90, 0x00, 0x00, 0x93];
+        program.resize(0x81, 0x00);
+        program[0x80] = 0x03;
+        let mut cpu = cpu_with_program(&program);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // CY, AC, OV and the odd parity of 0x80
+        assert_eq!(cpu.state().psw, 0xC5);
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x03);
+        assert_eq!(cpu.state().psw, 0xC4);
+    }
+
+    #[test]
+    fn psw_byte_and_fields_agree() {
+        for psw in 0..=0xFFu8 {
+            // MOV PSW, #psw
+            let mut cpu = cpu_with_program(&[0x75, 0xD0, psw]);
+            cpu.step().unwrap();
+            // P is recomputed from A (zero here), every other bit is as written
+            assert_eq!(cpu.state().psw, psw & !0x01);
+            assert_eq!(cpu.bank(), (psw >> 3) & 0x03);
+            assert_eq!(cpu.flags.carry(), psw >> 7);
+
+            cpu.set_bank(3 - cpu.bank());
+            assert_eq!(cpu.state().psw & !0x18, psw & !0x19);
+        }
+    }
 }
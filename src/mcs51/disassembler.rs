@@ -0,0 +1,355 @@
+use crate::mcs51::cpu::{AddressingMode, Instruction, Register};
+use crate::mcs51::symbols::SymbolTable;
+
+// special function registers common to every 8051
+pub const SFR_NAMES_8051: &[(u8, &str)] = &[
+    (0x80, "P0"),
+    (0x81, "SP"),
+    (0x82, "DPL"),
+    (0x83, "DPH"),
+    (0x87, "PCON"),
+    (0x88, "TCON"),
+    (0x89, "TMOD"),
+    (0x8A, "TL0"),
+    (0x8B, "TL1"),
+    (0x8C, "TH0"),
+    (0x8D, "TH1"),
+    (0x90, "P1"),
+    (0x98, "SCON"),
+    (0x99, "SBUF"),
+    (0xA0, "P2"),
+    (0xA8, "IE"),
+    (0xB0, "P3"),
+    (0xB8, "IP"),
+    (0xD0, "PSW"),
+    (0xE0, "ACC"),
+    (0xF0, "B"),
+];
+
+// column widths (in characters) of listing lines, the defaults line up like an sdas listing
+#[derive(Clone, Copy, Debug)]
+pub struct ListingFormat {
+    pub address_width: usize,
+    pub bytes_width: usize,
+}
+
+impl Default for ListingFormat {
+    fn default() -> ListingFormat {
+        ListingFormat {
+            address_width: 4,
+            bytes_width: 8,
+        }
+    }
+}
+
+pub struct Disassembler {
+    sfr_names: &'static [(u8, &'static str)],
+    symbols: SymbolTable,
+}
+
+impl Disassembler {
+    pub fn new(sfr_names: &'static [(u8, &'static str)]) -> Disassembler {
+        Disassembler {
+            sfr_names,
+            symbols: SymbolTable::default(),
+        }
+    }
+
+    // symbols replace raw code and iram addresses in the output
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn sfr_name(&self, address: u8) -> Option<&'static str> {
+        self.sfr_names
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map(|(_, name)| *name)
+    }
+
+    fn format_register(register: Register) -> &'static str {
+        match register {
+            Register::R0 => "R0",
+            Register::R1 => "R1",
+            Register::R2 => "R2",
+            Register::R3 => "R3",
+            Register::R4 => "R4",
+            Register::R5 => "R5",
+            Register::R6 => "R6",
+            Register::R7 => "R7",
+            Register::A => "A",
+            Register::C => "C",
+            Register::PC => "PC",
+            Register::DPTR => "DPTR",
+        }
+    }
+
+    fn format_direct(&self, address: u8) -> String {
+        // the lower 128 bytes are iram, the upper 128 are SFRs
+        let name = if address >= 0x80 {
+            self.sfr_name(address)
+        } else {
+            self.symbols.data_symbol(address)
+        };
+        match name {
+            Some(name) => String::from(name),
+            None => format!("0x{:02x}", address),
+        }
+    }
+
+    fn format_bit(&self, bit: u8) -> String {
+        // bits 0x00 - 0x7F live in iram 0x20 - 0x2F, the rest in SFRs with addresses ending in 0/8
+        if bit < 0x80 {
+            format!("0x{:02x}.{}", 0x20 + (bit >> 3), bit & 7)
+        } else {
+            format!("{}.{}", self.format_direct(bit & 0xF8), bit & 7)
+        }
+    }
+
+    fn format_operand(&self, mode: AddressingMode) -> String {
+        match mode {
+            AddressingMode::Immediate(imm8) => format!("#0x{:02x}", imm8),
+            AddressingMode::Register(register) => String::from(Self::format_register(register)),
+            AddressingMode::Bit(bit) => self.format_bit(bit),
+            AddressingMode::NotBit(bit) => format!("/{}", self.format_bit(bit)),
+            AddressingMode::Direct(address) => self.format_direct(address),
+            AddressingMode::Indirect(register) | AddressingMode::IndirectExternal(register) => {
+                format!("@{}", Self::format_register(register))
+            }
+            AddressingMode::IndirectCode(register) => {
+                format!("@A+{}", Self::format_register(register))
+            }
+        }
+    }
+
+    // symbol naming a code address, if any
+    pub fn code_label(&self, address: u16) -> Option<&str> {
+        self.symbols.code_symbol(address)
+    }
+
+    fn format_code_address(&self, address: u16) -> String {
+        match self.symbols.code_symbol(address) {
+            Some(name) => String::from(name),
+            None => format!("0x{:04x}", address),
+        }
+    }
+
+    // relative branches are rendered as their absolute target
+    fn format_relative(&self, next_address: u16, offset: i8) -> String {
+        self.format_code_address(next_address.wrapping_add(offset as i16 as u16))
+    }
+
+    // render an instruction, next_address is the address of the following instruction
+    pub fn format_instruction(&self, instruction: Instruction, next_address: u16) -> String {
+        match instruction {
+            Instruction::ACALL(address) => {
+                let address = (next_address & 0xF800) | address;
+                format!("ACALL {}", self.format_code_address(address))
+            }
+            Instruction::ADD(operand2) => format!("ADD A, {}", self.format_operand(operand2)),
+            Instruction::ADDC(operand2) => format!("ADDC A, {}", self.format_operand(operand2)),
+            Instruction::AJMP(address) => {
+                let address = (next_address & 0xF800) | address;
+                format!("AJMP {}", self.format_code_address(address))
+            }
+            Instruction::ANL(operand1, operand2) => format!(
+                "ANL {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2)
+            ),
+            Instruction::CJNE(operand1, operand2, offset) => format!(
+                "CJNE {}, {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2),
+                self.format_relative(next_address, offset)
+            ),
+            Instruction::CLR(address) => format!("CLR {}", self.format_operand(address)),
+            Instruction::CPL(address) => format!("CPL {}", self.format_operand(address)),
+            Instruction::Custom(opcode) => format!("<custom 0x{:02x}>", opcode),
+            Instruction::DA => String::from("DA A"),
+            Instruction::DEC(address) => format!("DEC {}", self.format_operand(address)),
+            Instruction::DIV => String::from("DIV AB"),
+            Instruction::DJNZ(address, offset) => format!(
+                "DJNZ {}, {}",
+                self.format_operand(address),
+                self.format_relative(next_address, offset)
+            ),
+            Instruction::INC(address) => format!("INC {}", self.format_operand(address)),
+            Instruction::Interrupt(vector, priority) => {
+                format!("<interrupt 0x{:04x}, priority {}>", vector, priority)
+            }
+            Instruction::JB(bit, offset) => format!(
+                "JB {}, {}",
+                self.format_operand(bit),
+                self.format_relative(next_address, offset)
+            ),
+            Instruction::JBC(bit, offset) => format!(
+                "JBC {}, {}",
+                self.format_operand(bit),
+                self.format_relative(next_address, offset)
+            ),
+            Instruction::JC(offset) => {
+                format!("JC {}", self.format_relative(next_address, offset))
+            }
+            Instruction::JMP => String::from("JMP @A+DPTR"),
+            Instruction::JNB(bit, offset) => format!(
+                "JNB {}, {}",
+                self.format_operand(bit),
+                self.format_relative(next_address, offset)
+            ),
+            Instruction::JNC(offset) => {
+                format!("JNC {}", self.format_relative(next_address, offset))
+            }
+            Instruction::JNZ(offset) => {
+                format!("JNZ {}", self.format_relative(next_address, offset))
+            }
+            Instruction::JZ(offset) => {
+                format!("JZ {}", self.format_relative(next_address, offset))
+            }
+            Instruction::LCALL(address) => {
+                format!("LCALL {}", self.format_code_address(address))
+            }
+            Instruction::LJMP(address) => format!("LJMP {}", self.format_code_address(address)),
+            Instruction::LoadDptr(data) => format!("MOV DPTR, #0x{:04x}", data),
+            Instruction::MOV(operand1, operand2) => format!(
+                "MOV {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2)
+            ),
+            Instruction::MOVC(operand) => format!("MOVC A, {}", self.format_operand(operand)),
+            Instruction::MOVX(operand1, operand2) => format!(
+                "MOVX {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2)
+            ),
+            Instruction::MUL => String::from("MUL AB"),
+            Instruction::NOP => String::from("NOP"),
+            Instruction::ORL(operand1, operand2) => format!(
+                "ORL {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2)
+            ),
+            Instruction::POP(address) => format!("POP {}", self.format_operand(address)),
+            Instruction::PUSH(address) => format!("PUSH {}", self.format_operand(address)),
+            Instruction::RET => String::from("RET"),
+            Instruction::RETI => String::from("RETI"),
+            Instruction::RL => String::from("RL A"),
+            Instruction::RLC => String::from("RLC A"),
+            Instruction::RR => String::from("RR A"),
+            Instruction::RRC => String::from("RRC A"),
+            Instruction::SETB(address) => format!("SETB {}", self.format_operand(address)),
+            Instruction::SJMP(offset) => {
+                format!("SJMP {}", self.format_relative(next_address, offset))
+            }
+            Instruction::SUBB(operand2) => format!("SUBB A, {}", self.format_operand(operand2)),
+            Instruction::SWAP => String::from("SWAP A"),
+            Instruction::XCH(operand2) => format!("XCH A, {}", self.format_operand(operand2)),
+            Instruction::XCHD(operand2) => format!("XCHD A, {}", self.format_operand(operand2)),
+            Instruction::XRL(operand1, operand2) => format!(
+                "XRL {}, {}",
+                self.format_operand(operand1),
+                self.format_operand(operand2)
+            ),
+        }
+    }
+
+    // render a listing line, "ADDR: BYTES  MNEMONIC OPERANDS" with bytes being the raw
+    // encoding of the instruction
+    pub fn format_listing_line(
+        &self,
+        format: ListingFormat,
+        address: u16,
+        bytes: &[u8],
+        instruction: Instruction,
+    ) -> String {
+        let bytes_column = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let next_address = address.wrapping_add(bytes.len() as u16);
+        format!(
+            "{:0address_width$X}: {:bytes_width$}  {}",
+            address,
+            bytes_column,
+            self.format_instruction(instruction, next_address),
+            address_width = format.address_width,
+            bytes_width = format.bytes_width
+        )
+    }
+
+    // listing line for a byte which isn't an instruction
+    pub fn format_data_line(&self, format: ListingFormat, address: u16, byte: u8) -> String {
+        format!(
+            "{:0address_width$X}: {:bytes_width$}  .db 0x{:02x}",
+            address,
+            format!("{:02X}", byte),
+            byte,
+            address_width = format.address_width,
+            bytes_width = format.bytes_width
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_addresses_render_as_byte_dot_bit() {
+        let disassembler = Disassembler::new(SFR_NAMES_8051);
+        for &(bit, text) in [
+            (0x00, "0x20.0"),
+            (0x25, "0x24.5"),
+            (0x7F, "0x2f.7"),
+            (0x90, "P1.0"),
+            (0xD7, "PSW.7"),
+            (0xE3, "ACC.3"),
+            // no name for 0xC8 on a plain 8051
+            (0xCA, "0xc8.2"),
+        ]
+        .iter()
+        {
+            assert_eq!(disassembler.format_bit(bit), text);
+        }
+        assert_eq!(
+            disassembler.format_instruction(Instruction::SETB(AddressingMode::Bit(0x90)), 2),
+            "SETB P1.0"
+        );
+        assert_eq!(
+            disassembler.format_instruction(Instruction::JB(AddressingMode::Bit(0x25), 0x01), 3),
+            "JB 0x24.5, 0x0004"
+        );
+        assert_eq!(
+            disassembler.format_operand(AddressingMode::NotBit(0xB2)),
+            "/P3.2"
+        );
+    }
+
+    #[test]
+    fn listing_line_columns() {
+        let disassembler = Disassembler::new(SFR_NAMES_8051);
+        let ljmp = Instruction::LJMP(0x1234);
+        assert_eq!(
+            disassembler.format_listing_line(
+                ListingFormat::default(),
+                0x0100,
+                &[0x02, 0x12, 0x34],
+                ljmp
+            ),
+            "0100: 02 12 34  LJMP 0x1234"
+        );
+        let wide = ListingFormat {
+            address_width: 6,
+            bytes_width: 12,
+        };
+        assert_eq!(
+            disassembler.format_listing_line(wide, 0x0100, &[0x02, 0x12, 0x34], ljmp),
+            "000100: 02 12 34      LJMP 0x1234"
+        );
+        assert_eq!(
+            disassembler.format_data_line(ListingFormat::default(), 0x0103, 0xA5),
+            "0103: A5        .db 0xa5"
+        );
+    }
+}
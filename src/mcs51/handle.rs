@@ -0,0 +1,260 @@
+use crate::mcs51::cpu::{CpuError, CpuState, InterruptSource, CPU};
+use crate::mcs51::memory::Memory;
+
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+// instructions run between polls for commands while running
+const RUN_BATCH: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    Step,
+    Run,
+    Pause,
+    SetBreakpoint(u16),
+    ClearBreakpoint(u16),
+    ReadState,
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    // execution stopped (after a step, pause or breakpoint) with the pc of the next instruction
+    Stopped(u16),
+    // execution stopped on an error
+    Fault(CpuError),
+    // a byte the firmware sent out
+    Output(u8),
+    State(CpuState),
+}
+
+// runs a cpu on a background thread, controlled over channels. the cpu shares its memory
+// through an Rc so it can't cross threads, instead it is built on the worker by a factory.
+pub struct EmulatorHandle {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EmulatorHandle {
+    // output is polled after every instruction for bytes to report, e.g. a serial port's
+    // transmitted()
+    pub fn spawn<A, F, O>(factory: F, output: O) -> EmulatorHandle
+    where
+        A: Memory + InterruptSource + 'static,
+        F: FnOnce() -> CPU<A> + Send + 'static,
+        O: FnMut(&mut A) -> Option<u8> + Send + 'static,
+    {
+        let (commands, command_receiver) = channel();
+        let (event_sender, events) = channel();
+        let thread = thread::spawn(move || {
+            let cpu = factory();
+            Worker::new(cpu, output, event_sender).run(command_receiver);
+        });
+        EmulatorHandle {
+            commands,
+            events,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        // the worker only exits on Quit or when dropped, a failed send has nothing to tell
+        let _ = self.commands.send(command);
+    }
+
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+}
+
+impl Drop for EmulatorHandle {
+    fn drop(&mut self) {
+        self.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Worker<A, O>
+where
+    A: Memory + InterruptSource,
+{
+    cpu: CPU<A>,
+    output: O,
+    events: Sender<Event>,
+    breakpoints: HashSet<u16>,
+}
+
+impl<A, O> Worker<A, O>
+where
+    A: Memory + InterruptSource,
+    O: FnMut(&mut A) -> Option<u8>,
+{
+    fn new(cpu: CPU<A>, output: O, events: Sender<Event>) -> Worker<A, O> {
+        Worker {
+            cpu,
+            output,
+            events,
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    fn stopped(&self) {
+        self.notify(Event::Stopped(self.cpu.state().program_counter));
+    }
+
+    // execute one instruction, returns false if it faulted
+    fn step(&mut self) -> bool {
+        if let Err(error) = self.cpu.step() {
+            self.notify(Event::Fault(error));
+            return false;
+        }
+        while let Some(data) = (self.output)(self.cpu.memory_mut()) {
+            self.notify(Event::Output(data));
+        }
+        true
+    }
+
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut running = false;
+        // the instruction under a breakpoint runs when resuming from it
+        let mut resuming = false;
+        loop {
+            // block for commands while paused, poll for them while running
+            let command = if running {
+                match commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            };
+
+            match command {
+                Some(Command::Step) => {
+                    running = false;
+                    if self.step() {
+                        self.stopped();
+                    }
+                }
+                Some(Command::Run) if !running => {
+                    running = true;
+                    resuming = true;
+                }
+                Some(Command::Pause) if running => {
+                    running = false;
+                    self.stopped();
+                }
+                Some(Command::SetBreakpoint(address)) => {
+                    self.breakpoints.insert(address);
+                }
+                Some(Command::ClearBreakpoint(address)) => {
+                    self.breakpoints.remove(&address);
+                }
+                Some(Command::ReadState) => self.notify(Event::State(self.cpu.state())),
+                Some(Command::Quit) => return,
+                Some(Command::Run) | Some(Command::Pause) | None => {}
+            }
+
+            if running {
+                for _ in 0..RUN_BATCH {
+                    let program_counter = self.cpu.state().program_counter;
+                    if !resuming && self.breakpoints.contains(&program_counter) {
+                        running = false;
+                        self.stopped();
+                        break;
+                    }
+                    resuming = false;
+                    if !self.step() {
+                        running = false;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcs51::memory::mock::cpu_with_program;
+
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    fn next_event(handle: &EmulatorHandle) -> Event {
+        handle
+            .events()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+    }
+
+    #[test]
+    fn step_breakpoint_and_quit() {
+        // NOP; NOP; NOP; INC A; SJMP -3 (to INC A)
+        let handle = EmulatorHandle::spawn(
+            || cpu_with_program(&[0x00, 0x00, 0x00, 0x04, 0x80, 0xFD]),
+            |_| None,
+        );
+
+        handle.send(Command::Step);
+        assert!(matches!(next_event(&handle), Event::Stopped(0x0001)));
+
+        handle.send(Command::SetBreakpoint(0x0003));
+        handle.send(Command::Run);
+        assert!(matches!(next_event(&handle), Event::Stopped(0x0003)));
+
+        // resuming runs the instruction under the breakpoint, then stops on it again
+        handle.send(Command::Run);
+        assert!(matches!(next_event(&handle), Event::Stopped(0x0003)));
+        handle.send(Command::ReadState);
+        match next_event(&handle) {
+            Event::State(state) => assert_eq!(state.accumulator, 1),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // the worker drops its end of the event channel when its thread exits
+        handle.send(Command::Quit);
+        assert_eq!(
+            handle.events().recv_timeout(Duration::from_secs(5)).err(),
+            Some(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn run_until_paused() {
+        // loop: INC A; SJMP loop
+        let handle = EmulatorHandle::spawn(|| cpu_with_program(&[0x04, 0x80, 0xFD]), |_| None);
+
+        handle.send(Command::Run);
+        std::thread::sleep(Duration::from_millis(10));
+        handle.send(Command::Pause);
+        match next_event(&handle) {
+            Event::Stopped(pc) => assert!(pc == 0x0000 || pc == 0x0001, "pc {:04x}", pc),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // pausing while stopped does nothing
+        handle.send(Command::Pause);
+        assert_eq!(
+            handle
+                .events()
+                .recv_timeout(Duration::from_millis(100))
+                .err(),
+            Some(RecvTimeoutError::Timeout)
+        );
+    }
+}
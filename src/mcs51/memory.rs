@@ -1,28 +1,157 @@
-use crate::mcs51::cpu::Address;
+use crate::mcs51::cpu::{Address, CpuError};
 
+#[cfg(feature = "elf")]
+use object::read::elf::{ElfFile32, ProgramHeader};
+
+use std::collections::VecDeque;
 use std::fs;
+use std::io;
 use std::path::Path;
 
 pub trait Memory {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str>;
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str>;
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError>;
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError>;
     fn tick(&mut self);
+
+    // read-modify-write instructions read a port's latch rather than its pins, memories
+    // without ports read as usual
+    fn read_latch(&mut self, address: Address) -> Result<u8, CpuError> {
+        self.read_memory(address)
+    }
+
+    // strict mode, set on the memory which also models the SFRs: the cpu then treats RET /
+    // RETI with the stack pointer below the register banks as a StackUnderflow
+    fn strict(&self) -> bool {
+        false
+    }
+
+    // the run is over, flush anything buffered (e.g. logs) so no output is lost
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct ROM {
     data: Vec<u8>,
+    writable: bool,
 }
 
 impl ROM {
+    // an erased device, every byte reads as 0xff
+    pub fn blank(size: usize) -> ROM {
+        ROM {
+            data: vec![0xff; size],
+            writable: false,
+        }
+    }
+
     pub fn load_from_binary(path: &Path) -> Result<ROM, Box<dyn std::error::Error + 'static>> {
         Ok(ROM {
             data: fs::read(path)?,
+            writable: false,
         })
     }
+
+    // load an intel hex image (e.g. the .ihx emitted by sdcc), unprogrammed bytes read as 0xff
+    pub fn load_from_ihx(path: &Path) -> Result<ROM, Box<dyn std::error::Error + 'static>> {
+        let mut data = Vec::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // hex digits are ascii, which also keeps the byte offsets below on char boundaries
+            if !line.is_ascii() || !line.starts_with(':') || line.len() < 11 || line.len() % 2 == 0
+            {
+                return Err("malformed intel hex record".into());
+            }
+
+            // decode record bytes and verify checksum
+            let mut record = Vec::with_capacity(line.len() / 2);
+            for i in (1..line.len()).step_by(2) {
+                record.push(u8::from_str_radix(&line[i..i + 2], 16)?);
+            }
+            if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err("intel hex checksum mismatch".into());
+            }
+            let length = record[0] as usize;
+            if record.len() != length + 5 {
+                return Err("intel hex record length mismatch".into());
+            }
+            let address = u16::from_be_bytes([record[1], record[2]]) as usize;
+            let payload = &record[4..4 + length];
+
+            match record[3] {
+                // data
+                0x00 => {
+                    if address + length > 0x10000 {
+                        return Err("intel hex data exceeds code address space".into());
+                    }
+                    if data.len() < address + length {
+                        data.resize(address + length, 0xff);
+                    }
+                    data[address..address + length].copy_from_slice(payload);
+                }
+                // end of file
+                0x01 => break,
+                // extended segment / linear address, only a zero base fits in code space
+                0x02 | 0x04 if payload.iter().any(|b| *b != 0) => {
+                    return Err("intel hex extended address outside code space".into());
+                }
+                // start address records have no meaning here
+                _ => {}
+            }
+        }
+        Ok(ROM {
+            data,
+            writable: false,
+        })
+    }
+
+    // load the PT_LOAD segments of an ELF image at their physical addresses, segments beyond
+    // the 64K code space (other address spaces) are skipped. unprogrammed bytes read as 0xff
+    #[cfg(feature = "elf")]
+    pub fn load_from_elf(path: &Path) -> Result<ROM, Box<dyn std::error::Error + 'static>> {
+        let file = fs::read(path)?;
+        let elf = ElfFile32::<object::Endianness>::parse(&*file)?;
+        let endian = elf.endian();
+        let mut data = Vec::new();
+        for segment in elf.elf_program_headers() {
+            if segment.p_type(endian) != object::elf::PT_LOAD {
+                continue;
+            }
+            let bytes = segment
+                .data(endian, &*file)
+                .map_err(|()| "truncated ELF segment")?;
+            let address = segment.p_paddr(endian) as usize;
+            let end = address + bytes.len();
+            if bytes.is_empty() || end > 0x10000 {
+                continue;
+            }
+            if data.len() < end {
+                data.resize(end, 0xff);
+            }
+            data[address..end].copy_from_slice(bytes);
+        }
+        Ok(ROM {
+            data,
+            writable: false,
+        })
+    }
+
+    // resize to the device size, bytes past the loaded image read as 0xff
+    pub fn resize(&mut self, size: usize) {
+        self.data.resize(size, 0xff);
+    }
+
+    // allow writes to code space, for self-modifying code (e.g. flash or shadow ram)
+    pub fn set_writable(&mut self, writable: bool) {
+        self.writable = writable;
+    }
 }
 
 impl Memory for ROM {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         let address = match address {
             Address::Code(a) => Some(a as usize),
             Address::ExternalData(a) => Some(a as usize),
@@ -33,16 +162,24 @@ impl Memory for ROM {
             if a < self.data.len() {
                 Ok(self.data[a])
             } else {
-                Err("address out of range")
+                Err(CpuError::Fault("address out of range"))
             }
         } else {
-            Err("unsupported addressing mode for ROM")
+            Err(CpuError::Fault("unsupported addressing mode for ROM"))
         }
     }
 
-    // all writes to ROM result in an error
-    fn write_memory(&mut self, _address: Address, _data: u8) -> Result<(), &'static str> {
-        Err("write attempted to read-only memory")
+    // writes to ROM result in an error unless it has been made writable
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        match address {
+            Address::Code(a) if !self.writable => Err(CpuError::WriteToRom(a)),
+            Address::Code(a) if (a as usize) < self.data.len() => {
+                self.data[a as usize] = data;
+                Ok(())
+            }
+            Address::Code(_) => Err(CpuError::Fault("address out of range")),
+            _ => Err(CpuError::Fault("write attempted to read-only memory")),
+        }
     }
 
     // rom has no tick function
@@ -62,7 +199,7 @@ impl RAM {
 }
 
 impl Memory for RAM {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         let address = match address {
             Address::Code(a) => Some(a as usize),
             Address::ExternalData(a) => Some(a as usize),
@@ -74,14 +211,16 @@ impl Memory for RAM {
             if a < self.data.len() {
                 Ok(self.data[a as usize])
             } else {
-                Err("address out of range")
+                Err(CpuError::Fault("address out of range"))
             }
         } else {
-            Err("unsupported addressing mode for RAM (read)")
+            Err(CpuError::Fault(
+                "unsupported addressing mode for RAM (read)",
+            ))
         }
     }
 
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str> {
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
         let address = match address {
             Address::ExternalData(a) => Some(a as usize),
             Address::InternalData(a) => Some(a as usize),
@@ -93,13 +232,303 @@ impl Memory for RAM {
                 self.data[a] = data;
                 Ok(())
             } else {
-                Err("address out of range")
+                Err(CpuError::Fault("address out of range"))
             }
         } else {
-            Err("unsupported addressing mode for RAM (write)")
+            Err(CpuError::Fault(
+                "unsupported addressing mode for RAM (write)",
+            ))
         }
     }
 
     // ram has no tick function
     fn tick(&mut self) {}
 }
+
+// stands in for an unpopulated external data bus. with nothing driving P0 the pulled-up bus
+// floats, so movx reads return a fixed value (typically 0xff) and movx writes are dropped.
+// the latest history accesses are kept (reads as None, writes with their data) to show where
+// firmware expects memory which isn't there, a history of 0 records nothing.
+pub struct OpenBus {
+    value: u8,
+    history: usize,
+    accesses: VecDeque<(u16, Option<u8>)>,
+}
+
+impl OpenBus {
+    pub fn new(value: u8, history: usize) -> OpenBus {
+        OpenBus {
+            value,
+            history,
+            accesses: VecDeque::with_capacity(history),
+        }
+    }
+
+    // what a read returns, without recording an access
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+
+    // the recorded accesses, oldest first
+    pub fn accesses(&self) -> &VecDeque<(u16, Option<u8>)> {
+        &self.accesses
+    }
+
+    fn record(&mut self, access: (u16, Option<u8>)) {
+        if self.history == 0 {
+            return;
+        }
+        if self.accesses.len() == self.history {
+            self.accesses.pop_front();
+        }
+        self.accesses.push_back(access);
+    }
+}
+
+impl Memory for OpenBus {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+        match address {
+            Address::ExternalData(a) => {
+                self.record((a, None));
+                Ok(self.value)
+            }
+            _ => Err(CpuError::Fault(
+                "unsupported addressing mode for open bus (read)",
+            )),
+        }
+    }
+
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        match address {
+            Address::ExternalData(a) => {
+                self.record((a, Some(data)));
+                Ok(())
+            }
+            _ => Err(CpuError::Fault(
+                "unsupported addressing mode for open bus (write)",
+            )),
+        }
+    }
+
+    // open bus has no tick function
+    fn tick(&mut self) {}
+}
+
+// memories for exercising the cpu core on its own in unit tests
+#[cfg(test)]
+pub mod mock {
+    use super::Memory;
+    use crate::mcs51::cpu::{Address, CpuError, InterruptSource, CPU};
+    use crate::mcs51::{get_bit, set_bit};
+
+    use std::rc::Rc;
+
+    // flat memory with no peripherals, every address space has its own storage
+    pub struct RamMemory {
+        code: Vec<u8>,
+        xdata: Vec<u8>,
+        iram: [u8; 256],
+        sfr: [u8; 128],
+        strict: bool,
+    }
+
+    impl RamMemory {
+        // program is loaded at code address 0
+        pub fn new(program: &[u8]) -> RamMemory {
+            let mut code = vec![0; 0x10000];
+            code[..program.len()].copy_from_slice(program);
+            RamMemory {
+                code,
+                xdata: vec![0; 0x10000],
+                iram: [0; 256],
+                sfr: [0; 128],
+                strict: false,
+            }
+        }
+
+        pub fn set_strict(&mut self, strict: bool) {
+            self.strict = strict;
+        }
+
+        // byte holding a bit, bits 0x00 - 0x7F live in iram 0x20 - 0x2F, the rest in SFRs
+        fn bit_byte(&mut self, bit: u8) -> &mut u8 {
+            if bit < 0x80 {
+                &mut self.iram[0x20 + (bit >> 3) as usize]
+            } else {
+                &mut self.sfr[((bit & 0xF8) - 0x80) as usize]
+            }
+        }
+    }
+
+    impl Memory for RamMemory {
+        fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+            match address {
+                Address::Code(a) => Ok(self.code[a as usize]),
+                Address::ExternalData(a) => Ok(self.xdata[a as usize]),
+                Address::InternalData(a) => Ok(self.iram[a as usize]),
+                Address::SpecialFunctionRegister(a) if a >= 0x80 => {
+                    Ok(self.sfr[(a - 0x80) as usize])
+                }
+                Address::SpecialFunctionRegister(_) => Err(CpuError::Fault("non-existant SFR")),
+                Address::Bit(bit) => Ok(get_bit(*self.bit_byte(bit), bit & 7)),
+            }
+        }
+
+        fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+            match address {
+                Address::Code(a) => return Err(CpuError::WriteToRom(a)),
+                Address::ExternalData(a) => self.xdata[a as usize] = data,
+                Address::InternalData(a) => self.iram[a as usize] = data,
+                Address::SpecialFunctionRegister(a) if a >= 0x80 => {
+                    self.sfr[(a - 0x80) as usize] = data
+                }
+                Address::SpecialFunctionRegister(_) => {
+                    return Err(CpuError::Fault("non-existant SFR"))
+                }
+                Address::Bit(bit) => {
+                    let byte = self.bit_byte(bit);
+                    *byte = set_bit(*byte, bit & 7, data != 0);
+                }
+            }
+            Ok(())
+        }
+
+        // ram memory has no tick function
+        fn tick(&mut self) {}
+
+        fn strict(&self) -> bool {
+            self.strict
+        }
+    }
+
+    // ram memory never requests interrupts
+    impl InterruptSource for RamMemory {
+        fn peek_vector(&mut self) -> Option<(u16, u8)> {
+            None
+        }
+
+        fn pop_vector(&mut self) {}
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Access {
+        Read(Address, u8),
+        Write(Address, u8),
+        // a read-modify-write read of a latch
+        ReadLatch(Address, u8),
+    }
+
+    // ram memory which records every access, so the exact sequence an instruction performs
+    // can be inspected
+    pub struct MockMemory {
+        ram: RamMemory,
+        accesses: Vec<Access>,
+    }
+
+    impl MockMemory {
+        pub fn new(program: &[u8]) -> MockMemory {
+            MockMemory {
+                ram: RamMemory::new(program),
+                accesses: Vec::new(),
+            }
+        }
+
+        pub fn accesses(&self) -> &[Access] {
+            &self.accesses
+        }
+
+        pub fn clear_accesses(&mut self) {
+            self.accesses.clear();
+        }
+    }
+
+    impl Memory for MockMemory {
+        fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+            let data = self.ram.read_memory(address)?;
+            self.accesses.push(Access::Read(address, data));
+            Ok(data)
+        }
+
+        fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+            self.ram.write_memory(address, data)?;
+            self.accesses.push(Access::Write(address, data));
+            Ok(())
+        }
+
+        fn read_latch(&mut self, address: Address) -> Result<u8, CpuError> {
+            let data = self.ram.read_memory(address)?;
+            self.accesses.push(Access::ReadLatch(address, data));
+            Ok(data)
+        }
+
+        // mock memory has no tick function
+        fn tick(&mut self) {}
+    }
+
+    // mock memory never requests interrupts
+    impl InterruptSource for MockMemory {
+        fn peek_vector(&mut self) -> Option<(u16, u8)> {
+            None
+        }
+
+        fn pop_vector(&mut self) {}
+    }
+
+    // a cpu over ram memory holding program at 0x0000, ready to step from reset (pc 0)
+    pub fn cpu_with_program(program: &[u8]) -> CPU<RamMemory> {
+        CPU::new(Rc::new(RamMemory::new(program)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihx_with_non_ascii_is_malformed() {
+        let path = std::env::temp_dir().join("mcs51_non_ascii.ihx");
+        // the two byte character straddles the fourth hex pair
+        fs::write(&path, ":0100000\u{e9}000\n:00000001FF\n").unwrap();
+        let result = ROM::load_from_ihx(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result.err().map(|error| error.to_string()),
+            Some("malformed intel hex record".to_string())
+        );
+    }
+
+    #[test]
+    fn open_bus_keeps_latest_accesses() {
+        let mut open_bus = OpenBus::new(0xff, 2);
+        for address in 0..3 {
+            assert_eq!(
+                open_bus.read_memory(Address::ExternalData(address)),
+                Ok(0xff)
+            );
+        }
+        open_bus
+            .write_memory(Address::ExternalData(0x10), 0x12)
+            .unwrap();
+        assert_eq!(open_bus.accesses(), &[(0x0002, None), (0x0010, Some(0x12))]);
+
+        let mut open_bus = OpenBus::new(0xff, 0);
+        open_bus.read_memory(Address::ExternalData(0)).unwrap();
+        assert!(open_bus.accesses().is_empty());
+    }
+
+    #[test]
+    fn rom_traps_code_writes_unless_writable() {
+        let mut rom = ROM::blank(0x100);
+        assert_eq!(
+            rom.write_memory(Address::Code(0x0042), 0x12),
+            Err(CpuError::WriteToRom(0x0042))
+        );
+        assert_eq!(rom.read_memory(Address::Code(0x0042)), Ok(0xff));
+
+        // self-modifying code
+        rom.set_writable(true);
+        assert_eq!(rom.write_memory(Address::Code(0x0042), 0x12), Ok(()));
+        assert_eq!(rom.read_memory(Address::Code(0x0042)), Ok(0x12));
+        assert!(rom.write_memory(Address::Code(0x0100), 0x12).is_err());
+    }
+}
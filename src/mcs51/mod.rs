@@ -1,7 +1,11 @@
+pub mod cfg;
 pub mod cpu;
+pub mod disassembler;
+pub mod handle;
 pub mod memory;
 pub mod peripherals;
 pub mod soc;
+pub mod symbols;
 
 use std::ops::{BitAnd, BitOr, Not, Shl};
 
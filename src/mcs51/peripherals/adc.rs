@@ -0,0 +1,171 @@
+use crate::mcs51::cpu::{Address, CpuError};
+use crate::mcs51::memory::Memory;
+
+use bitflags::bitflags;
+
+// machine cycles from ADCS being set until the result is available in ADAT
+const CONVERSION_CYCLES: u32 = 40;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct ADCON: u8 {
+        const AADR0 = 0b00000001;
+        const AADR1 = 0b00000010;
+        const AADR2 = 0b00000100;
+        const ADCS  = 0b00001000;
+        const ADCI  = 0b00010000;
+        const ADEX  = 0b00100000;
+    }
+}
+
+impl ADCON {
+    pub fn channel(&self) -> usize {
+        (self.bits & 0x07) as usize
+    }
+}
+
+// 8 channel, 8-bit successive approximation A/D converter
+pub struct Adc {
+    adcon: ADCON,
+    adat: u8,
+    remaining: u32,
+    sample: u8,
+
+    // analog side, in millivolts
+    vref_minus: u16,
+    vref_plus: u16,
+    inputs: [u16; 8],
+}
+
+impl Default for Adc {
+    fn default() -> Adc {
+        Adc {
+            adcon: ADCON::default(),
+            adat: 0,
+            remaining: 0,
+            sample: 0,
+            vref_minus: 0,
+            vref_plus: 5000,
+            inputs: [0; 8],
+        }
+    }
+}
+
+impl Adc {
+    pub fn new() -> Adc {
+        Adc::default()
+    }
+
+    // reference range (AVref- / AVref+) in millivolts
+    pub fn set_adc_reference(&mut self, vref_minus_mv: u16, vref_plus_mv: u16) {
+        self.vref_minus = vref_minus_mv;
+        self.vref_plus = vref_plus_mv;
+    }
+
+    // input voltage of an analog channel in millivolts
+    pub fn set_adc_input_mv(&mut self, channel: usize, mv: u16) {
+        self.inputs[channel & 7] = mv;
+    }
+
+    // a completed conversion requests an interrupt until software clears ADCI
+    pub fn interrupt_pending(&self) -> bool {
+        self.adcon.contains(ADCON::ADCI)
+    }
+
+    // conversion of the input against the reference range, clamped at either end
+    fn convert(&self, mv: u16) -> u8 {
+        if mv <= self.vref_minus || self.vref_plus <= self.vref_minus {
+            return 0;
+        }
+        let span = (self.vref_plus - self.vref_minus) as u32;
+        let value = (mv - self.vref_minus) as u32;
+        ((value * 255 + span / 2) / span).min(255) as u8
+    }
+}
+
+impl Memory for Adc {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+        match address {
+            Address::SpecialFunctionRegister(a) => match a {
+                0xC5 => Ok(self.adcon.bits),
+                0xC6 => Ok(self.adat),
+                _ => Err(CpuError::Fault("non-existant SFR")),
+            },
+            _ => Err(CpuError::Fault("unsupported addressing mode for adc")),
+        }
+    }
+
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        match address {
+            Address::SpecialFunctionRegister(a) => match a {
+                0xC5 => {
+                    // ADCS can only be set by software, it clears itself when the conversion ends
+                    let busy = self.adcon.contains(ADCON::ADCS);
+                    let start = !busy && data & ADCON::ADCS.bits != 0;
+                    self.adcon = ADCON::from_bits_truncate(data);
+                    self.adcon.set(ADCON::ADCS, busy);
+                    if start {
+                        self.adcon.insert(ADCON::ADCS);
+                        self.sample = self.convert(self.inputs[self.adcon.channel()]);
+                        self.remaining = CONVERSION_CYCLES;
+                    }
+                    Ok(())
+                }
+                // ADAT is read only
+                0xC6 => Ok(()),
+                _ => Err(CpuError::Fault("non-existant SFR")),
+            },
+            _ => Err(CpuError::Fault("unsupported addressing mode for adc")),
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.adat = self.sample;
+                self.adcon.remove(ADCON::ADCS);
+                self.adcon.insert(ADCON::ADCI);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // start a conversion of channel and wait for it to complete
+    fn conversion(adc: &mut Adc, channel: u8) -> u8 {
+        adc.write_memory(
+            Address::SpecialFunctionRegister(0xC5),
+            ADCON::ADCS.bits | channel,
+        )
+        .unwrap();
+        for _ in 0..CONVERSION_CYCLES {
+            assert!(!adc.interrupt_pending());
+            adc.tick();
+        }
+        assert!(adc.interrupt_pending());
+        adc.read_memory(Address::SpecialFunctionRegister(0xC6))
+            .unwrap()
+    }
+
+    #[test]
+    fn conversion_scales_to_reference() {
+        let mut adc = Adc::new();
+        adc.set_adc_reference(1000, 3000);
+        for &(mv, result) in [
+            (2000, 0x80),
+            (3000, 0xFF),
+            (4500, 0xFF),
+            (1000, 0x00),
+            (500, 0x00),
+        ]
+        .iter()
+        {
+            adc.set_adc_input_mv(3, mv);
+            assert_eq!(conversion(&mut adc, 3), result, "{} mV", mv);
+        }
+    }
+}
@@ -1 +1,3 @@
+pub mod adc;
+pub mod serial;
 pub mod timer;
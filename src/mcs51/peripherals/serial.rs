@@ -0,0 +1,239 @@
+use crate::mcs51::cpu::{Address, CpuError};
+use crate::mcs51::memory::Memory;
+
+use bitflags::bitflags;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+bitflags! {
+    #[derive(Default)]
+    pub struct SCON: u8 {
+        const RI  = 0b00000001;
+        const TI  = 0b00000010;
+        const RB8 = 0b00000100;
+        const TB8 = 0b00001000;
+        const REN = 0b00010000;
+        const SM2 = 0b00100000;
+        const SM1 = 0b01000000;
+        const SM0 = 0b10000000;
+    }
+}
+
+impl SCON {
+    pub fn mode(&self) -> u8 {
+        self.bits >> 6
+    }
+}
+
+#[derive(Default)]
+pub struct Serial {
+    scon: SCON,
+    smod: bool,
+
+    // shift registers, remaining time of the frame in flight (see frame_length)
+    tx_data: u8,
+    tx_remaining: u32,
+    rx_data: u8,
+    rx_remaining: u32,
+    sbuf: u8,
+
+    // host side of the serial line
+    rx_line: VecDeque<u8>,
+    tx_line: VecDeque<u8>,
+
+    // machine cycles since reset, for timestamping the transcript
+    cycle: u64,
+    // log of the traffic on the line, one "cycle rx|tx byte" line per byte
+    transcript: Option<Box<dyn Write>>,
+    // the write that stopped recording, reported by finish
+    transcript_error: Option<io::Error>,
+    // bytes to receive at a given cycle (replaying a transcript), in order
+    scheduled: VecDeque<(u64, u8)>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial::default()
+    }
+
+    // queue a byte arriving on RxD, it is shifted in once the receiver is enabled
+    pub fn receive(&mut self, data: u8) {
+        self.log("rx", data);
+        self.rx_line.push_back(data);
+    }
+
+    // queue a byte to arrive on RxD once the given number of machine cycles have passed since
+    // reset, bytes must be scheduled in order
+    pub fn receive_at(&mut self, cycle: u64, data: u8) {
+        self.scheduled.push_back((cycle, data));
+    }
+
+    // log the bytes received (when queued by the host) and transmitted (when the frame ends)
+    pub fn set_transcript(&mut self, transcript: Option<Box<dyn Write>>) {
+        self.transcript = transcript;
+        self.transcript_error = None;
+    }
+
+    fn log(&mut self, direction: &str, data: u8) {
+        if let Some(transcript) = self.transcript.as_mut() {
+            if let Err(error) = writeln!(transcript, "{} {} {:02x}", self.cycle, direction, data) {
+                self.transcript = None;
+                self.transcript_error = Some(error);
+            }
+        }
+    }
+
+    // pop a byte the firmware has finished sending on TxD
+    pub fn transmitted(&mut self) -> Option<u8> {
+        self.tx_line.pop_front()
+    }
+
+    // RI and TI are only ever cleared by software
+    pub fn interrupt_pending(&self) -> bool {
+        self.scon.intersects(SCON::RI | SCON::TI)
+    }
+
+    // baud rate doubler (PCON.SMOD)
+    pub fn set_smod(&mut self, smod: bool) {
+        self.smod = smod;
+    }
+
+    // modes 1 and 3 shift one bit per 32 (16 with SMOD) timer 1 overflows
+    pub fn timer1_overflow(&mut self) {
+        if self.clocked_by_timer1() {
+            self.shift(1);
+        }
+    }
+
+    fn clocked_by_timer1(&self) -> bool {
+        self.scon.mode() & 1 == 1
+    }
+
+    // frame length in timer 1 overflows (modes 1, 3) or oscillator clocks (modes 0, 2)
+    fn frame_length(&self) -> u32 {
+        let divisor = if self.smod { 16 } else { 32 };
+        match self.scon.mode() {
+            0 => 8 * 12,
+            1 => 10 * divisor,
+            2 => 11 * divisor * 2,
+            _ => 11 * divisor,
+        }
+    }
+
+    fn shift(&mut self, elapsed: u32) {
+        // transmitter
+        if self.tx_remaining > 0 {
+            self.tx_remaining = self.tx_remaining.saturating_sub(elapsed);
+            if self.tx_remaining == 0 {
+                self.log("tx", self.tx_data);
+                self.tx_line.push_back(self.tx_data);
+                self.scon.insert(SCON::TI);
+            }
+        }
+
+        // receiver, a frame completing while RI is still set is lost (overrun)
+        if self.rx_remaining > 0 {
+            self.rx_remaining = self.rx_remaining.saturating_sub(elapsed);
+            if self.rx_remaining == 0 && !self.scon.contains(SCON::RI) {
+                self.sbuf = self.rx_data;
+                self.scon.insert(SCON::RB8 | SCON::RI);
+            }
+        } else if self.scon.contains(SCON::REN) {
+            if let Some(data) = self.rx_line.pop_front() {
+                self.rx_data = data;
+                self.rx_remaining = self.frame_length();
+            }
+        }
+    }
+}
+
+impl Memory for Serial {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
+        match address {
+            Address::Bit(bit) => match bit {
+                0x98..=0x9F => {
+                    let flag = SCON::from_bits(1 << (bit & 7)).unwrap();
+                    if self.scon.contains(flag) {
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                }
+                _ => Err(CpuError::Fault("non-existant bit address")),
+            },
+            Address::SpecialFunctionRegister(a) => match a {
+                0x98 => Ok(self.scon.bits),
+                0x99 => Ok(self.sbuf),
+                _ => Err(CpuError::Fault("non-existant SFR")),
+            },
+            _ => Err(CpuError::Fault(
+                "unsupported addressing mode for serial port",
+            )),
+        }
+    }
+
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
+        match address {
+            Address::Bit(bit) => match bit {
+                0x98..=0x9F => {
+                    let flag = SCON::from_bits(1 << (bit & 7)).unwrap();
+                    self.scon.set(flag, data != 0);
+                    Ok(())
+                }
+                _ => Err(CpuError::Fault("non-existant bit address")),
+            },
+            Address::SpecialFunctionRegister(a) => match a {
+                0x98 => {
+                    self.scon.bits = data;
+                    Ok(())
+                }
+                0x99 => {
+                    // writing SBUF starts a transmission
+                    self.tx_data = data;
+                    self.tx_remaining = self.frame_length();
+                    Ok(())
+                }
+                _ => Err(CpuError::Fault("non-existant SFR")),
+            },
+            _ => Err(CpuError::Fault(
+                "unsupported addressing mode for serial port",
+            )),
+        }
+    }
+
+    // modes 0 and 2 are clocked from the oscillator, 12 clocks per machine cycle
+    fn tick(&mut self) {
+        // scheduled bytes arrive as if the host queued them between the previous cycle and this
+        while let Some(&(cycle, data)) = self.scheduled.front() {
+            if cycle > self.cycle {
+                break;
+            }
+            self.scheduled.pop_front();
+            self.receive(data);
+        }
+        self.cycle += 1;
+
+        if !self.clocked_by_timer1() {
+            self.shift(12);
+        }
+    }
+
+    // a frame still shifting out is delivered as if it had completed, then the transcript is
+    // flushed. a write that stopped recording earlier is returned here.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.tx_remaining > 0 {
+            self.tx_remaining = 0;
+            self.log("tx", self.tx_data);
+            self.tx_line.push_back(self.tx_data);
+            self.scon.insert(SCON::TI);
+        }
+        if let Some(error) = self.transcript_error.take() {
+            return Err(error);
+        }
+        match self.transcript.as_mut() {
+            Some(transcript) => transcript.flush(),
+            None => Ok(()),
+        }
+    }
+}
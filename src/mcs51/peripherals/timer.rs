@@ -1,4 +1,4 @@
-use crate::mcs51::cpu::Address;
+use crate::mcs51::cpu::{Address, CpuError};
 use crate::mcs51::memory::Memory;
 
 use bitflags::bitflags;
@@ -63,6 +63,17 @@ pub struct Timer {
     tmod: TMOD,
     t0_value: u16,
     t1_value: u16,
+    t1_overflowed: bool,
+
+    // INT0 / INT1 pin levels (active low)
+    int0: bool,
+    int1: bool,
+
+    // T0 / T1 counter input levels, and whether a falling edge was seen since the last tick
+    t0: bool,
+    t1: bool,
+    t0_edge: bool,
+    t1_edge: bool,
 }
 
 impl Timer {
@@ -72,6 +83,13 @@ impl Timer {
             tmod: TMOD::empty(),
             t0_value: 0,
             t1_value: 0,
+            t1_overflowed: false,
+            int0: true,
+            int1: true,
+            t0: true,
+            t1: true,
+            t0_edge: false,
+            t1_edge: false,
         }
     }
 
@@ -90,10 +108,89 @@ impl Timer {
     pub fn clear_timer1_overflow(&mut self) {
         self.tcon.remove(TCON::TF1)
     }
+
+    // drive the INT0 pin, a falling edge latches IE0 when edge triggered (IT0)
+    pub fn set_int0(&mut self, level: bool) {
+        if self.tcon.contains(TCON::IT0) && self.int0 && !level {
+            self.tcon.insert(TCON::IE0);
+        }
+        self.int0 = level;
+    }
+
+    pub fn set_int1(&mut self, level: bool) {
+        if self.tcon.contains(TCON::IT1) && self.int1 && !level {
+            self.tcon.insert(TCON::IE1);
+        }
+        self.int1 = level;
+    }
+
+    // drive the T0 / T1 pins, in counter mode (C/T) the timer counts their falling edges. the
+    // pins are sampled once per machine cycle, so at most one edge counts per tick.
+    pub fn set_t0(&mut self, level: bool) {
+        self.t0_edge |= self.t0 && !level;
+        self.t0 = level;
+    }
+
+    pub fn set_t1(&mut self, level: bool) {
+        self.t1_edge |= self.t1 && !level;
+        self.t1 = level;
+    }
+
+    // levels on the T0 / T1 pins
+    pub fn t0(&self) -> bool {
+        self.t0
+    }
+
+    pub fn t1(&self) -> bool {
+        self.t1
+    }
+
+    // levels on the INT0 / INT1 pins
+    pub fn int0(&self) -> bool {
+        self.int0
+    }
+
+    pub fn int1(&self) -> bool {
+        self.int1
+    }
+
+    pub fn get_external0_request(&self) -> bool {
+        self.tcon.contains(TCON::IE0)
+    }
+
+    pub fn get_external1_request(&self) -> bool {
+        self.tcon.contains(TCON::IE1)
+    }
+
+    // vectoring clears IE0 / IE1 only when edge triggered, when level triggered the flag
+    // follows the pin
+    pub fn acknowledge_external0(&mut self) {
+        if self.tcon.contains(TCON::IT0) {
+            self.tcon.remove(TCON::IE0);
+        }
+    }
+
+    pub fn acknowledge_external1(&mut self) {
+        if self.tcon.contains(TCON::IT1) {
+            self.tcon.remove(TCON::IE1);
+        }
+    }
+
+    // whether timer 1 rolled over during the last tick (serial port baud clock), this is
+    // independent of TF1 which may be left set or be borrowed by timer 0 in split mode
+    pub fn timer1_overflowed(&self) -> bool {
+        self.t1_overflowed
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory for Timer {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         match address {
             Address::Bit(bit) => {
                 // generally used for SFR bit access
@@ -106,7 +203,7 @@ impl Memory for Timer {
                             Ok(0)
                         }
                     }
-                    _ => Err("non-existant bit address"),
+                    _ => Err(CpuError::Fault("non-existant bit address")),
                 }
             }
             Address::SpecialFunctionRegister(a) => match a {
@@ -116,13 +213,13 @@ impl Memory for Timer {
                 0x8B => Ok(self.t1_value.to_le_bytes()[0]),
                 0x8C => Ok(self.t0_value.to_le_bytes()[1]),
                 0x8D => Ok(self.t1_value.to_le_bytes()[1]),
-                _ => Err("non-existant SFR"),
+                _ => Err(CpuError::Fault("non-existant SFR")),
             },
-            _ => Err("unsupported addressing mode for timer"),
+            _ => Err(CpuError::Fault("unsupported addressing mode for timer")),
         }
     }
 
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str> {
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
         match address {
             Address::Bit(bit) => {
                 // generally used for SFR bit access
@@ -132,7 +229,7 @@ impl Memory for Timer {
                         self.tcon.set(flag, data != 0);
                         Ok(())
                     }
-                    _ => Err("non-existant bit address"),
+                    _ => Err(CpuError::Fault("non-existant bit address")),
                 }
             }
             Address::SpecialFunctionRegister(a) => match a {
@@ -160,19 +257,37 @@ impl Memory for Timer {
                     self.t1_value = u16::from_le_bytes([self.t1_value.to_le_bytes()[0], data]);
                     Ok(())
                 }
-                _ => Err("non-existant SFR"),
+                _ => Err(CpuError::Fault("non-existant SFR")),
             },
-            _ => Err("unsupported addressing mode for timer"),
+            _ => Err(CpuError::Fault("unsupported addressing mode for timer")),
         }
     }
 
     fn tick(&mut self) {
-        // TODO: counter or interrupt mode
-        //   counter mode (C/T = 1), timer only counts if T0/1 is high
-        //   gate mode (GATE = 1), timer only counts if INT0/1 is high
+        // level triggered external interrupt requests follow the pins
+        if !self.tcon.contains(TCON::IT0) {
+            self.tcon.set(TCON::IE0, !self.int0);
+        }
+        if !self.tcon.contains(TCON::IT1) {
+            self.tcon.set(TCON::IE1, !self.int1);
+        }
+
+        // with GATE set a timer also needs its INT pin high to count (pulse width measurement)
+        let run0 =
+            self.tcon.contains(TCON::TR0) && (!self.tmod.contains(TMOD::T0_GATE) || self.int0);
+        let run1 =
+            self.tcon.contains(TCON::TR1) && (!self.tmod.contains(TMOD::T1_GATE) || self.int1);
+
+        // as a counter (C/T set) a running timer counts falling edges on T0 / T1 rather than
+        // machine cycles
+        let count0 = run0 && (!self.tmod.contains(TMOD::T0_CT) || self.t0_edge);
+        let count1 = run1 && (!self.tmod.contains(TMOD::T1_CT) || self.t1_edge);
+        self.t0_edge = false;
+        self.t1_edge = false;
+
         match self.tmod.timer0_mode() {
             TimerMode::Mode13Bit => {
-                if self.tcon.contains(TCON::TR0) {
+                if count0 {
                     let values = self.t0_value.to_le_bytes();
                     let lower = (values[0] & 0x1f) + 1;
                     let upper = if lower == 32 {
@@ -191,7 +306,7 @@ impl Memory for Timer {
                 }
             }
             TimerMode::Mode16Bit => {
-                if self.tcon.contains(TCON::TR0) {
+                if count0 {
                     self.t0_value = match self.t0_value.checked_add(1) {
                         Some(v) => v,
                         None => {
@@ -202,7 +317,7 @@ impl Memory for Timer {
                 }
             }
             TimerMode::Mode8BitAutoReload => {
-                if self.tcon.contains(TCON::TR0) {
+                if count0 {
                     let value = self.t0_value.to_le_bytes()[0];
                     let reload = self.t0_value.to_le_bytes()[1];
                     let next_value = match value.checked_add(1) {
@@ -220,7 +335,7 @@ impl Memory for Timer {
                     let values = self.t0_value.to_le_bytes();
                     [
                         // low timer
-                        if self.tcon.contains(TCON::TR0) {
+                        if count0 {
                             match values[0].checked_add(1) {
                                 Some(v) => v,
                                 None => {
@@ -247,10 +362,10 @@ impl Memory for Timer {
                 });
             }
         }
+        self.t1_overflowed = false;
         match self.tmod.timer1_mode() {
             TimerMode::Mode13Bit => {
-                if self.tcon.contains(TCON::TR1) || self.tmod.timer0_mode() == TimerMode::ModeSplit
-                {
+                if count1 || self.tmod.timer0_mode() == TimerMode::ModeSplit {
                     let values = self.t1_value.to_le_bytes();
                     let lower = (values[0] & 0x1f) + 1;
                     let upper = if lower == 32 {
@@ -260,6 +375,7 @@ impl Memory for Timer {
                                 if self.tmod.timer0_mode() != TimerMode::ModeSplit {
                                     self.tcon.insert(TCON::TF1);
                                 }
+                                self.t1_overflowed = true;
                                 0
                             }
                         }
@@ -271,22 +387,21 @@ impl Memory for Timer {
                 }
             }
             TimerMode::Mode16Bit => {
-                if self.tcon.contains(TCON::TR1) || self.tmod.timer0_mode() == TimerMode::ModeSplit
-                {
+                if count1 || self.tmod.timer0_mode() == TimerMode::ModeSplit {
                     self.t1_value = match self.t1_value.checked_add(1) {
                         Some(v) => v,
                         None => {
                             if self.tmod.timer0_mode() != TimerMode::ModeSplit {
                                 self.tcon.insert(TCON::TF1);
                             }
+                            self.t1_overflowed = true;
                             0
                         }
                     }
                 }
             }
             TimerMode::Mode8BitAutoReload => {
-                if self.tcon.contains(TCON::TR1) || self.tmod.timer0_mode() == TimerMode::ModeSplit
-                {
+                if count1 || self.tmod.timer0_mode() == TimerMode::ModeSplit {
                     let value = self.t1_value.to_le_bytes()[0];
                     let reload = self.t1_value.to_le_bytes()[1];
                     let next_value = match value.checked_add(1) {
@@ -295,13 +410,110 @@ impl Memory for Timer {
                             if self.tmod.timer0_mode() != TimerMode::ModeSplit {
                                 self.tcon.insert(TCON::TF1);
                             }
+                            self.t1_overflowed = true;
                             reload
                         }
                     };
                     self.t1_value = u16::from_le_bytes([next_value, reload]);
                 }
             }
-            TimerMode::ModeSplit => panic!("timer 1 does not support split mode"),
+            // timer 1 in mode 3 is stopped, it holds its count
+            TimerMode::ModeSplit => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer1_mode3_holds_count() {
+        let mut timer = Timer::new();
+        timer
+            .write_memory(Address::SpecialFunctionRegister(0x89), 0x30)
+            .unwrap();
+        timer
+            .write_memory(Address::SpecialFunctionRegister(0x8B), 0x12)
+            .unwrap();
+        timer.write_memory(Address::Bit(0x8E), 1).unwrap();
+        for _ in 0..10 {
+            timer.tick();
+        }
+        assert_eq!(
+            timer.read_memory(Address::SpecialFunctionRegister(0x8B)),
+            Ok(0x12)
+        );
+        assert!(!timer.timer1_overflowed());
+    }
+
+    // a running timer 0 in the given TMOD mode
+    fn running_timer0(tmod: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer
+            .write_memory(Address::SpecialFunctionRegister(0x89), tmod)
+            .unwrap();
+        timer.write_memory(Address::Bit(0x8C), 1).unwrap();
+        timer
+    }
+
+    fn timer0_count(timer: &mut Timer) -> u16 {
+        let low = timer.read_memory(Address::SpecialFunctionRegister(0x8A));
+        let high = timer.read_memory(Address::SpecialFunctionRegister(0x8C));
+        u16::from_le_bytes([low.unwrap(), high.unwrap()])
+    }
+
+    #[test]
+    fn gated_timer0_counts_while_int0_high() {
+        // GATE, 16-bit timer
+        let mut timer = running_timer0(0x09);
+        // (INT0, cycles, count afterwards)
+        for &(level, cycles, count) in [
+            (false, 10, 0),
+            (true, 25, 25),
+            (false, 10, 25),
+            (true, 5, 30),
+        ]
+        .iter()
+        {
+            timer.set_int0(level);
+            for _ in 0..cycles {
+                timer.tick();
+            }
+            assert_eq!(timer0_count(&mut timer), count);
         }
+
+        // without GATE the pin doesn't matter
+        let mut timer = running_timer0(0x01);
+        timer.set_int0(false);
+        for _ in 0..10 {
+            timer.tick();
+        }
+        assert_eq!(timer0_count(&mut timer), 10);
+    }
+
+    #[test]
+    fn counter_mode_counts_t0_falling_edges() {
+        // C/T, 16-bit counter
+        let mut timer = running_timer0(0x05);
+        for _ in 0..10 {
+            timer.tick();
+        }
+        assert_eq!(timer0_count(&mut timer), 0);
+
+        for _ in 0..7 {
+            timer.set_t0(false);
+            timer.tick();
+            timer.tick();
+            timer.set_t0(true);
+            timer.tick();
+        }
+        assert_eq!(timer0_count(&mut timer), 7);
+
+        // a stopped counter ignores edges
+        timer.write_memory(Address::Bit(0x8C), 0).unwrap();
+        timer.set_t0(false);
+        timer.tick();
+        assert_eq!(timer0_count(&mut timer), 7);
     }
 }
@@ -1,14 +1,46 @@
-use crate::mcs51::cpu::{Address, InterruptSource, CPU};
+use crate::mcs51::cpu::{self, Address, CpuError, CPU};
 use crate::mcs51::memory::{Memory, RAM};
+use crate::mcs51::peripherals::adc::Adc;
+use crate::mcs51::peripherals::serial::Serial;
 use crate::mcs51::peripherals::timer::Timer;
 use crate::mcs51::{get_bit, set_bit};
 
 use bitflags::bitflags;
 
+use std::io;
 use std::rc::Rc;
 
+// special function registers of the P80C550, including the A/D converter
+pub const SFR_NAMES: &[(u8, &str)] = &[
+    (0x80, "P0"),
+    (0x81, "SP"),
+    (0x82, "DPL"),
+    (0x83, "DPH"),
+    (0x87, "PCON"),
+    (0x88, "TCON"),
+    (0x89, "TMOD"),
+    (0x8A, "TL0"),
+    (0x8B, "TL1"),
+    (0x8C, "TH0"),
+    (0x8D, "TH1"),
+    (0x90, "P1"),
+    (0x98, "SCON"),
+    (0x99, "SBUF"),
+    (0xA0, "P2"),
+    (0xA8, "IE"),
+    (0xB0, "P3"),
+    (0xB8, "IP"),
+    (0xC0, "P4"),
+    (0xC4, "P5"),
+    (0xC5, "ADCON"),
+    (0xC6, "ADAT"),
+    (0xD0, "PSW"),
+    (0xE0, "ACC"),
+    (0xF0, "B"),
+];
+
 bitflags! {
-    struct IE: u8 {
+    pub struct IE: u8 {
         const EX0 = 0b00000001;
         const ET0 = 0b00000010;
         const EX1 = 0b00000100;
@@ -39,24 +71,114 @@ bitflags! {
     }
 }
 
+// external interrupt pins, INT0 is P3.2 and INT1 is P3.3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExternalInt {
+    Int0,
+    Int1,
+}
+
+// timer counter inputs, T0 is P3.4 and T1 is P3.5
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterInput {
+    T0,
+    T1,
+}
+
 impl IE {
+    // the pending source with the highest natural priority (the one to_vector picks)
+    pub fn highest(&self) -> IE {
+        IE::from_bits_truncate(self.bits & self.bits.wrapping_neg()) - IE::EA
+    }
+
+    // simultaneous requests at the same priority level are polled in datasheet order
     pub fn to_vector(&self) -> Option<u16> {
-        if self.contains(IE::EX0) {
-            Some(0x03)
-        } else if self.contains(IE::ET0) {
-            Some(0x0B)
-        } else if self.contains(IE::EX1) {
-            Some(0x13)
-        } else if self.contains(IE::ET1) {
-            Some(0x1B)
-        } else if self.contains(IE::ES) {
-            Some(0x23)
-        } else if self.contains(IE::EAD) {
-            Some(0x2B)
-        } else if self.contains(IE::EWD) {
-            Some(0x33)
-        } else {
-            None
+        InterruptLine::ALL
+            .iter()
+            .find(|source| self.bits & source.enable_bit() != 0)
+            .map(|source| source.vector())
+    }
+}
+
+// member of the family being emulated, decides which on-chip peripherals exist. timer 2, a
+// second data pointer and the watchdog aren't modeled on any variant yet, so there is no 8052.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChipVariant {
+    // 128 bytes of iram
+    I8051,
+    // 128 bytes of iram and the A/D converter
+    P80C550,
+}
+
+impl ChipVariant {
+    pub fn iram_size(&self) -> usize {
+        128
+    }
+
+    pub fn has_adc(&self) -> bool {
+        *self == ChipVariant::P80C550
+    }
+}
+
+// interrupt request lines of the P80C550 (named apart from the cpu's InterruptSource trait)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptLine {
+    External0,
+    Timer0,
+    External1,
+    Timer1,
+    Serial,
+    Adc,
+    Watchdog,
+}
+
+impl InterruptLine {
+    // in the order simultaneous requests at the same priority level are polled
+    pub const ALL: [InterruptLine; 7] = [
+        InterruptLine::External0,
+        InterruptLine::Timer0,
+        InterruptLine::External1,
+        InterruptLine::Timer1,
+        InterruptLine::Serial,
+        InterruptLine::Adc,
+        InterruptLine::Watchdog,
+    ];
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            InterruptLine::External0 => 0x03,
+            InterruptLine::Timer0 => 0x0B,
+            InterruptLine::External1 => 0x13,
+            InterruptLine::Timer1 => 0x1B,
+            InterruptLine::Serial => 0x23,
+            InterruptLine::Adc => 0x2B,
+            InterruptLine::Watchdog => 0x33,
+        }
+    }
+
+    // mask of the source's enable bit in IE
+    pub fn enable_bit(&self) -> u8 {
+        match self {
+            InterruptLine::External0 => IE::EX0.bits,
+            InterruptLine::Timer0 => IE::ET0.bits,
+            InterruptLine::External1 => IE::EX1.bits,
+            InterruptLine::Timer1 => IE::ET1.bits,
+            InterruptLine::Serial => IE::ES.bits,
+            InterruptLine::Adc => IE::EAD.bits,
+            InterruptLine::Watchdog => IE::EWD.bits,
+        }
+    }
+
+    // mask of the source's priority bit in IP
+    pub fn priority_bit(&self) -> u8 {
+        match self {
+            InterruptLine::External0 => IP::PX0.bits,
+            InterruptLine::Timer0 => IP::PT0.bits,
+            InterruptLine::External1 => IP::PX1.bits,
+            InterruptLine::Timer1 => IP::PT1.bits,
+            InterruptLine::Serial => IP::PS.bits,
+            InterruptLine::Adc => IP::PAD.bits,
+            InterruptLine::Watchdog => IP::PWD.bits,
         }
     }
 }
@@ -66,16 +188,26 @@ where
     A: Memory,
     B: Memory,
 {
+    variant: ChipVariant,
     rom: Rc<A>,
     xram: Rc<B>,
     iram: RAM,
 
     // 8051 peripherals
     timer: Timer,
+    serial: Serial,
+    adc: Option<Adc>,
 
     // 8051 io ports
     port0: u8,
+    // port 0 is open drain, what is outside the chip decides what a released pin reads
+    port0_pullups: bool,
+    port0_driven: u8,
+    port0_levels: u8,
     port1: u8,
+    // pins of port 1 held by an external driver and the levels they're held at
+    port1_driven: u8,
+    port1_levels: u8,
     port2: u8,
     port3: u8,
 
@@ -83,6 +215,11 @@ where
     ie: IE,
     ip: IP,
     pcon: PCON,
+    raised: IE,
+
+    // backing store for SFRs not modeled by any peripheral
+    sfr: [u8; 128],
+    strict: bool,
 }
 
 impl<A, B> Peripherals<A, B>
@@ -90,40 +227,194 @@ where
     A: Memory,
     B: Memory,
 {
-    pub fn new(rom: Rc<A>, xram: Rc<B>) -> Peripherals<A, B> {
+    pub fn new(rom: Rc<A>, xram: Rc<B>, variant: ChipVariant) -> Peripherals<A, B> {
         Peripherals {
+            variant,
             rom: rom,
-            iram: RAM::create_with_size(128),
+            iram: RAM::create_with_size(variant.iram_size()),
             xram: xram,
             timer: Timer::new(),
+            serial: Serial::new(),
+            adc: if variant.has_adc() {
+                Some(Adc::new())
+            } else {
+                None
+            },
             port0: 0xff,
+            port0_pullups: false,
+            port0_driven: 0,
+            port0_levels: 0,
             port1: 0xff,
+            port1_driven: 0,
+            port1_levels: 0,
             port2: 0xff,
             port3: 0xff,
             ie: IE::empty(),
             ip: IP::empty(),
             pcon: PCON::empty(),
+            raised: IE::empty(),
+            sfr: [0; 128],
+            strict: false,
+        }
+    }
+
+    // replace or inspect the program memory
+    pub fn rom_mut(&mut self) -> &mut A {
+        Rc::get_mut(&mut self.rom).unwrap()
+    }
+
+    // devices on the external data bus
+    pub fn xram_mut(&mut self) -> &mut B {
+        Rc::get_mut(&mut self.xram).unwrap()
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn variant(&self) -> ChipVariant {
+        self.variant
+    }
+
+    // analog inputs and reference are set through the converter, if the part has one
+    pub fn adc_mut(&mut self) -> Option<&mut Adc> {
+        self.adc.as_mut()
+    }
+
+    // latch an interrupt request (e.g. from a host modeled peripheral), it stays pending until
+    // the cpu vectors to it or it is cleared
+    pub fn raise_interrupt(&mut self, line: InterruptLine) {
+        self.raised
+            .insert(IE::from_bits_truncate(line.enable_bit()));
+    }
+
+    pub fn clear_interrupt(&mut self, line: InterruptLine) {
+        self.raised
+            .remove(IE::from_bits_truncate(line.enable_bit()));
+    }
+
+    // external pull-ups on port 0, without them a pin latched high floats
+    pub fn set_port0_pullups(&mut self, pullups: bool) {
+        self.port0_pullups = pullups;
+    }
+
+    // pins of port 0 held by an external driver (driven mask) and the levels they're held at
+    pub fn drive_port0(&mut self, driven: u8, levels: u8) {
+        self.port0_driven = driven;
+        self.port0_levels = levels;
+    }
+
+    // level on the port 0 pins. unlike ports 1 to 3 there are no internal pull-ups, a pin the
+    // latch releases reads the external driver, else the pull-ups, else floats (modeled as 0).
+    // read-modify-write instructions read the latch instead (see read_latch).
+    fn port0_pins(&self) -> u8 {
+        let released = if self.port0_pullups { 0xff } else { 0x00 };
+        let external = (self.port0_levels & self.port0_driven) | (released & !self.port0_driven);
+        self.port0 & external
+    }
+
+    // pins of port 1 held by an external driver (driven mask) and the levels they're held at
+    pub fn drive_port1(&mut self, driven: u8, levels: u8) {
+        self.port1_driven = driven;
+        self.port1_levels = levels;
+    }
+
+    // level on the port 1 pins, the internal pull-ups hold a released pin high unless the
+    // external driver pulls it low
+    fn port1_pins(&self) -> u8 {
+        self.port1 & ((self.port1_levels & self.port1_driven) | !self.port1_driven)
+    }
+
+    // drive an external interrupt pin from the host, the request follows the pin when level
+    // triggered or is latched on a falling edge when edge triggered (IT0 / IT1)
+    pub fn set_int_pin(&mut self, pin: ExternalInt, level: bool) {
+        match pin {
+            ExternalInt::Int0 => self.timer.set_int0(level),
+            ExternalInt::Int1 => self.timer.set_int1(level),
+        }
+    }
+
+    // drive a timer counter input from the host, a timer in counter mode (C/T) counts its
+    // falling edges
+    pub fn set_counter_pin(&mut self, pin: CounterInput, level: bool) {
+        match pin {
+            CounterInput::T0 => self.timer.set_t0(level),
+            CounterInput::T1 => self.timer.set_t1(level),
+        }
+    }
+
+    // level on the port 3 pins, the latch pulls a pin low and so does the host on INT0 / INT1
+    // and T0 / T1
+    fn port3_pins(&self) -> u8 {
+        let int0 = if self.timer.int0() { 0x04 } else { 0x00 };
+        let int1 = if self.timer.int1() { 0x08 } else { 0x00 };
+        let t0 = if self.timer.t0() { 0x10 } else { 0x00 };
+        let t1 = if self.timer.t1() { 0x20 } else { 0x00 };
+        self.port3 & (!0x3C | int0 | int1 | t0 | t1)
+    }
+
+    // in strict mode, accessing an SFR no peripheral models is an error rather than plain storage
+    // and the cpu reports RET / RETI with the stack pointer below 0x08 as a StackUnderflow
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    fn read_unmodeled_sfr(&self, address: u8) -> Result<u8, CpuError> {
+        if self.strict {
+            Err(CpuError::UnimplementedSfr(address))
+        } else {
+            Ok(self.sfr[(address & 0x7f) as usize])
+        }
+    }
+
+    fn write_unmodeled_sfr(&mut self, address: u8, data: u8) -> Result<(), CpuError> {
+        if self.strict {
+            Err(CpuError::UnimplementedSfr(address))
+        } else {
+            self.sfr[(address & 0x7f) as usize] = data;
+            Ok(())
         }
     }
 
     fn collect_interrupts(&self) -> IE {
         // collect bitflags for interrupts
         let mut interrupts = IE::empty();
+        if self.timer.get_external0_request() {
+            interrupts.insert(IE::EX0);
+        }
+        if self.timer.get_external1_request() {
+            interrupts.insert(IE::EX1);
+        }
         if self.timer.get_timer0_overflow() {
             interrupts.insert(IE::ET0);
         }
         if self.timer.get_timer1_overflow() {
             interrupts.insert(IE::ET1);
         }
+        if self.serial.interrupt_pending() {
+            interrupts.insert(IE::ES);
+        }
+        if self.adc.as_ref().is_some_and(|adc| adc.interrupt_pending()) {
+            interrupts.insert(IE::EAD);
+        }
+        interrupts.insert(self.raised);
 
         // compute enabled interrupts
         self.ie.intersection(interrupts)
     }
 
+    // vectoring clears the timer overflow flags and edge triggered external requests, RI / TI
+    // and ADCI are left for software to clear
     fn clear_pending_interrupt(&mut self, interrupts: IE) {
-        if interrupts.contains(IE::ET0) {
+        let interrupt = interrupts.highest();
+        self.raised.remove(interrupt);
+        if interrupt == IE::EX0 {
+            self.timer.acknowledge_external0();
+        } else if interrupt == IE::EX1 {
+            self.timer.acknowledge_external1();
+        } else if interrupt == IE::ET0 {
             self.timer.clear_timer0_overflow();
-        } else if interrupts.contains(IE::ET1) {
+        } else if interrupt == IE::ET1 {
             self.timer.clear_timer1_overflow();
         }
     }
@@ -134,7 +425,7 @@ where
     A: Memory,
     B: Memory,
 {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         match address {
             Address::Code(a) => Rc::get_mut(&mut self.rom)
                 .unwrap()
@@ -146,9 +437,10 @@ where
             Address::Bit(bit) => {
                 // generally used for SFR bit access
                 match bit {
-                    0x80..=0x87 => Ok(get_bit(self.port0, bit & 7)),
+                    0x80..=0x87 => Ok(get_bit(self.port0_pins(), bit & 7)),
                     0x88..=0x8F => self.timer.read_memory(address),
-                    0x90..=0x97 => Ok(get_bit(self.port1, bit & 7)),
+                    0x90..=0x97 => Ok(get_bit(self.port1_pins(), bit & 7)),
+                    0x98..=0x9F => self.serial.read_memory(address),
                     0xA0..=0xA7 => Ok(get_bit(self.port2, bit & 7)),
                     0xA8..=0xAF => {
                         let flag = IE::from_bits(1 << (bit & 7)).unwrap();
@@ -158,33 +450,56 @@ where
                             Ok(0)
                         }
                     }
-                    0xB0..=0xB7 => Ok(get_bit(self.port3, bit & 7)),
+                    0xB0..=0xB7 => Ok(get_bit(self.port3_pins(), bit & 7)),
                     0xB8..=0xBF => {
-                        let flag = IP::from_bits(1 << (bit & 7)).unwrap();
-                        if self.ip.contains(flag) {
+                        // IP.7 is unimplemented and reads 0
+                        let flag = IP::from_bits_truncate(1 << (bit & 7));
+                        if self.ip.intersects(flag) {
                             Ok(1)
                         } else {
                             Ok(0)
                         }
                     }
-                    _ => Err("non-existant bit address"),
+                    _ => Ok(get_bit(self.read_unmodeled_sfr(bit & 0xF8)?, bit & 7)),
                 }
             }
+            // SFRs owned by a peripheral go to it, the rest is plain storage (or an error in
+            // strict mode). the core registers are handled by the cpu and don't arrive here.
             Address::SpecialFunctionRegister(a) => match a {
-                0x80 => Ok(self.port0),
+                0x80 => Ok(self.port0_pins()),
+                0x87 => Ok(self.pcon.bits),
                 0x88 | 0x89 | 0x8A | 0x8B | 0x8C | 0x8D => self.timer.read_memory(address),
-                0x90 => Ok(self.port1),
+                0x90 => Ok(self.port1_pins()),
+                0x98 | 0x99 => self.serial.read_memory(address),
                 0xA0 => Ok(self.port2),
                 0xA8 => Ok(self.ie.bits),
-                0xB0 => Ok(self.port3),
-                0xB7 => Ok(self.pcon.bits),
+                0xB0 => Ok(self.port3_pins()),
                 0xB8 => Ok(self.ip.bits),
-                _ => Err("non-existant SFR"),
+                0xC5 | 0xC6 if self.adc.is_some() => {
+                    self.adc.as_mut().unwrap().read_memory(address)
+                }
+                _ => self.read_unmodeled_sfr(a),
             },
         }
     }
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str> {
+    fn read_latch(&mut self, address: Address) -> Result<u8, CpuError> {
+        match address {
+            Address::SpecialFunctionRegister(0x80) => Ok(self.port0),
+            Address::SpecialFunctionRegister(0x90) => Ok(self.port1),
+            Address::SpecialFunctionRegister(0xA0) => Ok(self.port2),
+            Address::SpecialFunctionRegister(0xB0) => Ok(self.port3),
+            Address::Bit(bit @ 0x80..=0x87) => Ok(get_bit(self.port0, bit & 7)),
+            Address::Bit(bit @ 0x90..=0x97) => Ok(get_bit(self.port1, bit & 7)),
+            Address::Bit(bit @ 0xA0..=0xA7) => Ok(get_bit(self.port2, bit & 7)),
+            Address::Bit(bit @ 0xB0..=0xB7) => Ok(get_bit(self.port3, bit & 7)),
+            _ => self.read_memory(address),
+        }
+    }
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
         match address {
+            Address::Code(a) => Rc::get_mut(&mut self.rom)
+                .unwrap()
+                .write_memory(Address::Code(a), data),
             Address::InternalData(a) => self.iram.write_memory(Address::InternalData(a), data),
             Address::ExternalData(a) => Rc::get_mut(&mut self.xram)
                 .unwrap()
@@ -201,6 +516,7 @@ where
                         self.port1 = set_bit(self.port1, bit & 7, data != 0);
                         Ok(())
                     }
+                    0x98..=0x9F => self.serial.write_memory(address, data),
                     0xA0..=0xA7 => {
                         self.port2 = set_bit(self.port2, bit & 7, data != 0);
                         Ok(())
@@ -215,11 +531,14 @@ where
                         Ok(())
                     }
                     0xB8..=0xBF => {
-                        let flag = IP::from_bits(1 << (bit & 7)).unwrap();
+                        let flag = IP::from_bits_truncate(1 << (bit & 7));
                         self.ip.set(flag, data != 0);
                         Ok(())
                     }
-                    _ => Err("non-existant bit address"),
+                    _ => {
+                        let octet = self.read_unmodeled_sfr(bit & 0xF8)?;
+                        self.write_unmodeled_sfr(bit & 0xF8, set_bit(octet, bit & 7, data != 0))
+                    }
                 }
             }
             Address::SpecialFunctionRegister(a) => match a {
@@ -227,11 +546,17 @@ where
                     self.port0 = data;
                     Ok(())
                 }
+                0x87 => {
+                    self.pcon.bits = data;
+                    self.serial.set_smod(self.pcon.contains(PCON::SMOD));
+                    Ok(())
+                }
                 0x88 | 0x89 | 0x8A | 0x8B | 0x8C | 0x8D => self.timer.write_memory(address, data),
                 0x90 => {
                     self.port1 = data;
                     Ok(())
                 }
+                0x98 | 0x99 => self.serial.write_memory(address, data),
                 0xA0 => {
                     self.port2 = data;
                     Ok(())
@@ -244,30 +569,46 @@ where
                     self.port3 = data;
                     Ok(())
                 }
-                0xB7 => {
-                    self.pcon.bits = data;
-                    Ok(())
-                }
                 0xB8 => {
                     self.ip.bits = data;
                     Ok(())
                 }
-                _ => Err("non-existant SFR"),
+                0xC5 | 0xC6 if self.adc.is_some() => {
+                    self.adc.as_mut().unwrap().write_memory(address, data)
+                }
+                _ => self.write_unmodeled_sfr(a, data),
             },
-            _ => Err("unsupported addressing mode for memory mapper (write)"),
         }
     }
 
-    // tick updates peripherals
+    // tick updates peripherals, always in this order so the result doesn't depend on how the
+    // board was assembled (serial follows timer as timer 1 clocks it)
     fn tick(&mut self) {
         Rc::get_mut(&mut self.rom).unwrap().tick();
         Rc::get_mut(&mut self.xram).unwrap().tick();
         self.iram.tick();
         self.timer.tick();
+        self.serial.tick();
+        if let Some(adc) = self.adc.as_mut() {
+            adc.tick();
+        }
+        if self.timer.timer1_overflowed() {
+            self.serial.timer1_overflow();
+        }
+    }
+
+    fn strict(&self) -> bool {
+        self.strict
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Rc::get_mut(&mut self.rom).unwrap().finish()?;
+        Rc::get_mut(&mut self.xram).unwrap().finish()?;
+        self.serial.finish()
     }
 }
 
-impl<A, B> InterruptSource for Peripherals<A, B>
+impl<A, B> cpu::InterruptSource for Peripherals<A, B>
 where
     A: Memory,
     B: Memory,
@@ -280,12 +621,11 @@ where
             let low_priority_interrupts = IE::from_bits_truncate(!self.ip.bits & interrupts.bits);
 
             // return vector
-            if let Some(vector) = high_priority_interrupts.to_vector() {
-                Some((vector, 1))
-            } else if let Some(vector) = low_priority_interrupts.to_vector() {
-                Some((vector, 0))
-            } else {
-                None
+            match high_priority_interrupts.to_vector() {
+                Some(vector) => Some((vector, 1)),
+                None => low_priority_interrupts
+                    .to_vector()
+                    .map(|vector| (vector, 0)),
             }
         } else {
             None
@@ -307,11 +647,491 @@ where
     }
 }
 
-pub fn create<A, B>(rom: Rc<A>, xram: Rc<B>) -> CPU<Peripherals<A, B>>
+pub fn create<A, B>(rom: Rc<A>, xram: Rc<B>, variant: ChipVariant) -> CPU<Peripherals<A, B>>
 where
     A: Memory,
     B: Memory,
 {
-    let soc = Rc::new(Peripherals::new(rom, xram));
-    CPU::new(soc)
+    let soc = Rc::new(Peripherals::new(rom, xram, variant));
+    let mut cpu = CPU::new(soc);
+    cpu.set_sfr_names(SFR_NAMES);
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcs51::memory::ROM;
+
+    // a part with program at 0x0000 in a 64K rom and 64K of external ram
+    fn variant_with_program(program: &[u8], variant: ChipVariant) -> CPU<Peripherals<ROM, RAM>> {
+        let mut rom = ROM::blank(0x10000);
+        rom.set_writable(true);
+        for (address, byte) in program.iter().enumerate() {
+            rom.write_memory(Address::Code(address as u16), *byte)
+                .unwrap();
+        }
+        create(
+            Rc::new(rom),
+            Rc::new(RAM::create_with_size(0x10000)),
+            variant,
+        )
+    }
+
+    fn soc_with_program(program: &[u8]) -> CPU<Peripherals<ROM, RAM>> {
+        variant_with_program(program, ChipVariant::P80C550)
+    }
+
+    #[test]
+    fn inc_port1_increments_latch_not_pins() {
+        // MOV P1, #0x0F; INC P1
+        let mut cpu = soc_with_program(&[0x75, 0x90, 0x0F, 0x05, 0x90]);
+        cpu.memory_mut().drive_port1(0xFF, 0xF0);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.memory_mut()
+                .read_memory(Address::SpecialFunctionRegister(0x90)),
+            Ok(0x00)
+        );
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port1, 0x10);
+    }
+
+    #[test]
+    fn port0_floats_where_port1_pulls_up() {
+        // MOV A, P0; MOV B, P1 with both latches at 0xFF and nothing driving the pins
+        let mut cpu = soc_with_program(&[0xE5, 0x80, 0x85, 0x90, 0xF0]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().accumulator, 0x00);
+        assert_eq!(cpu.state().b, 0xFF);
+
+        // with pull-ups fitted port 0 reads high too
+        cpu.memory_mut().set_port0_pullups(true);
+        assert_eq!(
+            cpu.memory_mut()
+                .read_memory(Address::SpecialFunctionRegister(0x80)),
+            Ok(0xFF)
+        );
+    }
+
+    #[test]
+    fn orl_port0_keeps_floating_latch() {
+        // ORL P0, #0x01 with the latch at 0xFF, no pull-ups and nothing driving the pins
+        let mut cpu = soc_with_program(&[0x43, 0x80, 0x01]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port0, 0xFF);
+    }
+
+    #[test]
+    fn anl_port3_keeps_latch_under_held_pins() {
+        // ANL P3, #0xFF; CPL P3.2; JBC P3.2, +0 with INT0 held low
+        let mut cpu = soc_with_program(&[0x53, 0xB0, 0xFF, 0xB2, 0xB2, 0x10, 0xB2, 0x00]);
+        cpu.memory_mut().set_int_pin(ExternalInt::Int0, false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port3, 0xFF);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port3, 0xFB);
+        // the latch bit is clear, so JBC neither jumps nor writes
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port3, 0xFB);
+        assert_eq!(cpu.state().program_counter, 8);
+    }
+
+    #[test]
+    fn djnz_port2_counts_latch() {
+        // MOV P2, #0x02; DJNZ P2, -3 (to itself)
+        let mut cpu = soc_with_program(&[0x75, 0xA0, 0x02, 0xD5, 0xA0, 0xFD]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port2, 0x01);
+        assert_eq!(cpu.state().program_counter, 3);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory_mut().port2, 0x00);
+        assert_eq!(cpu.state().program_counter, 6);
+    }
+
+    // MOV IE, #0x82 (EA, ET0); NOP; NOP; NOP ... timer 0 vector: RETI
+    fn interrupt_program() -> Vec<u8> {
+        let mut program = vec![0x75, 0xA8, 0x82, 0x00, 0x00, 0x00, 0x00, 0x00];
+        program.resize(0x0B, 0x00);
+        program.push(0x32);
+        program
+    }
+
+    #[test]
+    fn reti_runs_one_instruction_before_pending_interrupt() {
+        let mut cpu = soc_with_program(&interrupt_program());
+        cpu.step().unwrap();
+
+        // the write to IE holds off the request for one instruction
+        cpu.memory_mut().raise_interrupt(InterruptLine::Timer0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 4);
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 0x0B);
+
+        // the same source requests again before the handler returns
+        cpu.memory_mut().raise_interrupt(InterruptLine::Timer0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 4);
+        let record = cpu.step_detailed().unwrap();
+        assert!(matches!(record.instruction, cpu::Instruction::NOP));
+        assert_eq!(cpu.state().program_counter, 5);
+        let record = cpu.step_detailed().unwrap();
+        assert!(matches!(
+            record.instruction,
+            cpu::Instruction::Interrupt(0x0B, 0)
+        ));
+        assert_eq!(cpu.state().program_counter, 0x0B);
+    }
+
+    #[test]
+    fn port3_bit_write_does_not_inhibit_interrupts() {
+        // MOV IE, #0x82; SETB P3.0 ... timer 0 vector: RETI
+        let mut program = interrupt_program();
+        program[3..5].copy_from_slice(&[0xD2, 0xB0]);
+        let mut cpu = soc_with_program(&program);
+        cpu.step().unwrap();
+        cpu.memory_mut().raise_interrupt(InterruptLine::Timer0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 5);
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 0x0B);
+    }
+
+    #[test]
+    fn unimplemented_ip_bit_reads_zero() {
+        // SETB 0xBF; MOV C, 0xBF
+        let mut cpu = soc_with_program(&[0xD2, 0xBF, 0xA2, 0xBF]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().psw & 0x80, 0);
+    }
+
+    #[test]
+    fn trace_names_adcon() {
+        // MOV ADCON, #0x01
+        let mut cpu = soc_with_program(&[0x75, 0xC5, 0x01]);
+        let path = std::env::temp_dir().join("p80c550_trace_adcon.txt");
+        cpu.trace_to(&path).unwrap();
+        cpu.step().unwrap();
+        cpu.finish().unwrap();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(trace.contains("ADCON"), "{}", trace);
+    }
+
+    #[test]
+    fn i8051_has_no_adc_or_timer2() {
+        // MOV R0, #0x80; MOV A, @R0
+        let mut cpu = variant_with_program(&[0x78, 0x80, 0xE6], ChipVariant::I8051);
+        cpu.step().unwrap();
+        assert!(cpu.step().is_err());
+
+        let soc = cpu.memory_mut();
+        assert!(soc.adc_mut().is_none());
+        soc.set_strict(true);
+        for sfr in [0xC5, 0xC8].iter() {
+            assert_eq!(
+                soc.read_memory(Address::SpecialFunctionRegister(*sfr)),
+                Err(CpuError::UnimplementedSfr(*sfr))
+            );
+        }
+    }
+
+    #[test]
+    fn p80c550_has_adc() {
+        let mut cpu = soc_with_program(&[]);
+        let soc = cpu.memory_mut();
+        assert!(soc.adc_mut().is_some());
+        soc.set_strict(true);
+        assert!(soc
+            .read_memory(Address::SpecialFunctionRegister(0xC5))
+            .is_ok());
+        assert_eq!(
+            soc.read_memory(Address::SpecialFunctionRegister(0xC8)),
+            Err(CpuError::UnimplementedSfr(0xC8))
+        );
+    }
+
+    #[test]
+    fn interrupt_vectors_match_datasheet() {
+        let vectors: Vec<u16> = InterruptLine::ALL
+            .iter()
+            .map(|line| line.vector())
+            .collect();
+        assert_eq!(vectors, [0x03, 0x0B, 0x13, 0x1B, 0x23, 0x2B, 0x33]);
+    }
+
+    #[test]
+    fn cleared_line_does_not_vector() {
+        let mut cpu = soc_with_program(&interrupt_program());
+        cpu.step().unwrap();
+        cpu.memory_mut().raise_interrupt(InterruptLine::Timer0);
+        cpu.memory_mut().clear_interrupt(InterruptLine::Timer0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().program_counter, 5);
+    }
+
+    // firmware with main at 0x0080 behind the reset vector, and each handler (from 0x0100 on)
+    // reached by an LJMP at its line's vector
+    fn with_vectors(main: &[u8], handlers: &[(InterruptLine, &[u8])]) -> Vec<u8> {
+        let mut program = vec![0x00; 0x0100];
+        program[0x0000..0x0003].copy_from_slice(&[0x02, 0x00, 0x80]);
+        program[0x0080..0x0080 + main.len()].copy_from_slice(main);
+        for (line, handler) in handlers.iter() {
+            let [high, low] = (program.len() as u16).to_be_bytes();
+            let vector = line.vector() as usize;
+            program[vector..vector + 3].copy_from_slice(&[0x02, high, low]);
+            program.extend_from_slice(handler);
+        }
+        program
+    }
+
+    #[test]
+    fn timer0_overflow_runs_handler() {
+        // MOV TMOD, #0x01; MOV TH0, #0xFF; MOV TL0, #0xFE; MOV IE, #0x82; SETB TR0; SJMP $
+        let main = [
+            0x75, 0x89, 0x01, 0x75, 0x8C, 0xFF, 0x75, 0x8A, 0xFE, 0x75, 0xA8, 0x82, 0xD2, 0x8C,
+            0x80, 0xFE,
+        ];
+        // MOV 0x30, #0x01; RETI
+        let handler = [0x75, 0x30, 0x01, 0x32];
+        let mut cpu = soc_with_program(&with_vectors(&main, &[(InterruptLine::Timer0, &handler)]));
+        for _ in 0..20 {
+            cpu.step().unwrap();
+        }
+        let soc = cpu.memory_mut();
+        assert_eq!(soc.read_memory(Address::InternalData(0x30)), Ok(0x01));
+        assert_eq!(cpu.state().program_counter, 0x008E);
+    }
+
+    #[test]
+    fn strict_mode_rejects_unmodeled_sfrs() {
+        // MOV P1, #0x5A; MOV A, P1; MOV 0xC8, #0x01; MOV A, 0xC8
+        let program = [0x75, 0x90, 0x5A, 0xE5, 0x90, 0x75, 0xC8, 0x01, 0xE5, 0xC8];
+        let mut cpu = soc_with_program(&program);
+        cpu.memory_mut().set_strict(true);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state().accumulator, 0x5A);
+        assert_eq!(cpu.step(), Err(CpuError::UnimplementedSfr(0xC8)));
+        assert_eq!(
+            cpu.memory_mut()
+                .read_memory(Address::SpecialFunctionRegister(0xC8)),
+            Err(CpuError::UnimplementedSfr(0xC8))
+        );
+
+        // without strict mode the same SFR is plain storage
+        let mut cpu = soc_with_program(&program);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.state().accumulator, 0x01);
+    }
+
+    #[test]
+    fn strict_mode_also_checks_stack_underflow() {
+        // RET with SP at its reset value 0x07
+        let mut cpu = soc_with_program(&[0x22]);
+        cpu.memory_mut().set_strict(true);
+        assert_eq!(cpu.step(), Err(CpuError::StackUnderflow(0x07)));
+    }
+
+    #[test]
+    fn received_byte_vectors_to_serial_handler() {
+        // MOV SCON, #0x90; MOV IE, #0x90; SJMP $
+        let main = [0x75, 0x98, 0x90, 0x75, 0xA8, 0x90, 0x80, 0xFE];
+        // MOV 0x30, SBUF; CLR RI; RETI
+        let handler = [0x85, 0x99, 0x30, 0xC2, 0x98, 0x32];
+        let mut cpu = soc_with_program(&with_vectors(&main, &[(InterruptLine::Serial, &handler)]));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.memory_mut().serial_mut().receive(0x5A);
+        let mut vectored = false;
+        for _ in 0..200 {
+            let record = cpu.step_detailed().unwrap();
+            if matches!(record.instruction, cpu::Instruction::Interrupt(0x23, _)) {
+                vectored = true;
+                break;
+            }
+            assert_eq!(record.program_counter, 0x0086);
+        }
+        assert!(vectored);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.state().program_counter, 0x0086);
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x30)),
+            Ok(0x5A)
+        );
+    }
+
+    #[test]
+    fn simultaneous_requests_serviced_in_datasheet_order() {
+        use InterruptLine::*;
+
+        // MOV IE, #0x8F; SJMP $ ... each handler: RETI
+        let main = [0x75, 0xA8, 0x8F, 0x80, 0xFE];
+        let lines = [External0, Timer0, External1, Timer1];
+        let handlers: Vec<(InterruptLine, &[u8])> =
+            lines.iter().map(|line| (*line, &[0x32][..])).collect();
+        let program = with_vectors(&main, &handlers);
+
+        // the order the requests are raised in doesn't matter
+        let orders = [
+            lines,
+            [Timer1, External1, Timer0, External0],
+            [Timer0, External1, Timer1, External0],
+        ];
+        for order in orders.iter() {
+            let mut cpu = soc_with_program(&program);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            for line in order.iter() {
+                cpu.memory_mut().raise_interrupt(*line);
+            }
+            let mut vectors = Vec::new();
+            for _ in 0..20 {
+                if let cpu::Instruction::Interrupt(vector, _) =
+                    cpu.step_detailed().unwrap().instruction
+                {
+                    vectors.push(vector);
+                }
+            }
+            assert_eq!(vectors, [0x03, 0x0B, 0x13, 0x1B], "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn vectoring_clears_ie0_only_when_edge_triggered() {
+        // (IT0, IE0 once in the handler)
+        for &(edge, pending) in [(true, 0), (false, 1)].iter() {
+            // SETB IT0 (or two NOPs); MOV IE, #0x81; SJMP $ ... INT0 handler: SJMP $
+            let mut main = vec![0x00, 0x00, 0x75, 0xA8, 0x81, 0x80, 0xFE];
+            if edge {
+                main[0..2].copy_from_slice(&[0xD2, 0x88]);
+            }
+            let program = with_vectors(&main, &[(InterruptLine::External0, &[0x80, 0xFE])]);
+            let mut cpu = soc_with_program(&program);
+            for _ in 0..3 {
+                cpu.step().unwrap();
+            }
+            cpu.memory_mut().set_int_pin(ExternalInt::Int0, false);
+            let mut vectored = false;
+            for _ in 0..4 {
+                if let cpu::Instruction::Interrupt(0x03, _) =
+                    cpu.step_detailed().unwrap().instruction
+                {
+                    vectored = true;
+                    break;
+                }
+            }
+            assert!(vectored);
+            // the pin is still held low
+            assert_eq!(
+                cpu.memory_mut().read_memory(Address::Bit(0x89)),
+                Ok(pending),
+                "edge {}",
+                edge
+            );
+        }
+    }
+
+    #[test]
+    fn completed_conversion_vectors_to_adc_handler() {
+        // MOV IE, #0xA0; MOV ADCON, #0x0B; SJMP $
+        let main = [0x75, 0xA8, 0xA0, 0x75, 0xC5, 0x0B, 0x80, 0xFE];
+        // MOV 0x30, ADAT; ANL ADCON, #0xEF; RETI
+        let handler = [0x85, 0xC6, 0x30, 0x53, 0xC5, 0xEF, 0x32];
+        let mut cpu = soc_with_program(&with_vectors(&main, &[(InterruptLine::Adc, &handler)]));
+        cpu.memory_mut()
+            .adc_mut()
+            .unwrap()
+            .set_adc_input_mv(3, 2500);
+        let mut vectored = false;
+        for _ in 0..100 {
+            let record = cpu.step_detailed().unwrap();
+            if matches!(record.instruction, cpu::Instruction::Interrupt(0x2B, _)) {
+                vectored = true;
+                break;
+            }
+        }
+        assert!(vectored);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // back in the main loop with ADCI cleared and no further request
+        assert_eq!(cpu.state().program_counter, 0x0086);
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x30)),
+            Ok(0x80)
+        );
+        for _ in 0..10 {
+            cpu.step().unwrap();
+            assert_eq!(cpu.state().program_counter, 0x0086);
+        }
+    }
+
+    #[test]
+    fn int0_falling_edge_vectors_once() {
+        // SETB IT0; MOV IE, #0x81; SJMP $ ... INT0 handler: INC 0x30; RETI
+        let main = [0xD2, 0x88, 0x75, 0xA8, 0x81, 0x80, 0xFE];
+        let handler = [0x05, 0x30, 0x32];
+        let program = with_vectors(&main, &[(InterruptLine::External0, &handler)]);
+        let mut cpu = soc_with_program(&program);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        let mut interrupts = 0;
+        // held low well past the handler, then released and left high
+        for &level in [false, true].iter() {
+            cpu.memory_mut().set_int_pin(ExternalInt::Int0, level);
+            for _ in 0..30 {
+                if let cpu::Instruction::Interrupt(0x03, _) =
+                    cpu.step_detailed().unwrap().instruction
+                {
+                    interrupts += 1;
+                }
+            }
+        }
+        assert_eq!(interrupts, 1);
+        assert_eq!(
+            cpu.memory_mut().read_memory(Address::InternalData(0x30)),
+            Ok(0x01)
+        );
+        assert_eq!(cpu.state().program_counter, 0x0085);
+    }
+
+    #[test]
+    fn request_waits_while_ea_is_cleared() {
+        // MOV IE, #0x82; CLR EA; NOP; NOP; NOP; SETB EA; NOP; NOP ... timer 0 handler: RETI
+        let main = [
+            0x75, 0xA8, 0x82, 0xC2, 0xAF, 0x00, 0x00, 0x00, 0xD2, 0xAF, 0x00, 0x00,
+        ];
+        let program = with_vectors(&main, &[(InterruptLine::Timer0, &[0x32])]);
+        let mut cpu = soc_with_program(&program);
+        // the reset vector's LJMP, then MOV IE
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.memory_mut().raise_interrupt(InterruptLine::Timer0);
+        // CLR EA runs as the instruction after the IE write, then the request stays pending
+        for &program_counter in [0x0085, 0x0086, 0x0087, 0x0088, 0x008A, 0x008B].iter() {
+            let record = cpu.step_detailed().unwrap();
+            assert!(!matches!(
+                record.instruction,
+                cpu::Instruction::Interrupt(_, _)
+            ));
+            assert_eq!(cpu.state().program_counter, program_counter);
+        }
+        let record = cpu.step_detailed().unwrap();
+        assert!(matches!(
+            record.instruction,
+            cpu::Instruction::Interrupt(0x0B, _)
+        ));
+    }
 }
@@ -0,0 +1,179 @@
+#[cfg(feature = "elf")]
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Space {
+    Code,
+    Data,
+    ExternalData,
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    code: BTreeMap<u16, String>,
+    data: BTreeMap<u8, String>,
+    xdata: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn load_sdcc_map(path: &Path) -> Result<SymbolTable, Box<dyn std::error::Error + 'static>> {
+        Ok(SymbolTable::parse_sdcc_map(&fs::read_to_string(path)?))
+    }
+
+    // code symbols (named symbols defined in executable sections) from an ELF image
+    #[cfg(feature = "elf")]
+    pub fn load_elf(path: &Path) -> Result<SymbolTable, Box<dyn std::error::Error + 'static>> {
+        let file = fs::read(path)?;
+        let elf = object::File::parse(&*file)?;
+        let mut symbols = SymbolTable::default();
+        for symbol in elf.symbols() {
+            let section = match symbol.section_index() {
+                Some(index) => elf.section_by_index(index)?,
+                None => continue,
+            };
+            let name = symbol.name()?;
+            if section.kind() == SectionKind::Text && !name.is_empty() && symbol.address() <= 0xffff
+            {
+                symbols.insert_code(symbol.address() as u16, name);
+            }
+        }
+        Ok(symbols)
+    }
+
+    // parse the global symbol listing of an sdld (sdcc linker) .map file. each area lists its
+    // globals as "[C:] 00000062  _main  module", the space comes from the optional prefix or
+    // else from the attributes of the enclosing area.
+    pub fn parse_sdcc_map(map: &str) -> SymbolTable {
+        let mut symbols = SymbolTable::default();
+        let mut area_space = None;
+        for line in map.lines() {
+            // area header, e.g. "CSEG   00000062   0000002B =   43. bytes (REL,CON,CODE)"
+            if let Some(start) = line.find("bytes (") {
+                let attributes = &line[start + 7..];
+                let name = line.split_whitespace().next().unwrap_or("");
+                area_space = if attributes.contains("XDATA") || name == "XSEG" || name == "PSEG" {
+                    Some(Space::ExternalData)
+                } else if attributes.contains("CODE") {
+                    Some(Space::Code)
+                } else if attributes.contains("DATA") || name == "DSEG" || name == "OSEG" {
+                    Some(Space::Data)
+                } else {
+                    None
+                };
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace().peekable();
+            let space = match tokens.peek() {
+                Some(&"C:") => Some(Space::Code),
+                Some(&"D:") | Some(&"I:") => Some(Space::Data),
+                Some(&"X:") => Some(Space::ExternalData),
+                _ => None,
+            };
+            if space.is_some() {
+                tokens.next();
+            }
+            let (value, name) = match (tokens.next(), tokens.next()) {
+                (Some(value), Some(name)) => (value, name),
+                _ => continue,
+            };
+            let value = match u32::from_str_radix(value, 16) {
+                Ok(value) if value <= 0xffff => value as u16,
+                _ => continue,
+            };
+            // skip the assembler's internal symbols and area lengths
+            if name.starts_with('.') || name.starts_with("l_") {
+                continue;
+            }
+            match space.or(area_space) {
+                Some(Space::Code) => symbols.insert_code(value, name),
+                Some(Space::Data) if value < 0x100 => symbols.insert_data(value as u8, name),
+                Some(Space::ExternalData) => symbols.insert_xdata(value, name),
+                _ => {}
+            }
+        }
+        symbols
+    }
+
+    // the first symbol registered at an address is the one displayed
+    pub fn insert_code(&mut self, address: u16, name: &str) {
+        self.code
+            .entry(address)
+            .or_insert_with(|| String::from(name));
+    }
+
+    pub fn insert_data(&mut self, address: u8, name: &str) {
+        self.data
+            .entry(address)
+            .or_insert_with(|| String::from(name));
+    }
+
+    pub fn insert_xdata(&mut self, address: u16, name: &str) {
+        self.xdata
+            .entry(address)
+            .or_insert_with(|| String::from(name));
+    }
+
+    pub fn code_symbol(&self, address: u16) -> Option<&str> {
+        self.code.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn data_symbol(&self, address: u8) -> Option<&str> {
+        self.data.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn xdata_symbol(&self, address: u16) -> Option<&str> {
+        self.xdata.get(&address).map(|name| name.as_str())
+    }
+
+    // reverse lookup, e.g. to place a breakpoint on a function
+    pub fn code_address(&self, name: &str) -> Option<u16> {
+        self.code
+            .iter()
+            .find(|(_, symbol)| symbol.as_str() == name)
+            .map(|(address, _)| *address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+Area                                    Addr        Size        Decimal Bytes (Attributes)
+--------------------------------        ----        ----        ------- ----- ------------
+CSEG                                0000006E    0000002B =          43. bytes (REL,CON,CODE)
+
+      Value  Global                              Global Defined In Module
+      -----  --------------------------------   ------------------------
+     C:   0000006E  _delay                             main
+     C:   00000080  _main                              main
+          00000099  l_CSEG
+DSEG                                00000008    00000002 =           2. bytes (REL,CON)
+
+      Value  Global                              Global Defined In Module
+      -----  --------------------------------   ------------------------
+          00000008  _counter                           main
+XSEG                                00000000    00000010 =          16. bytes (REL,CON,XDATA)
+
+      Value  Global                              Global Defined In Module
+      -----  --------------------------------   ------------------------
+          00000000  _buffer                            main
+";
+
+    #[test]
+    fn parse_sdcc_map_symbols() {
+        let symbols = SymbolTable::parse_sdcc_map(MAP);
+        assert_eq!(symbols.code_symbol(0x006E), Some("_delay"));
+        assert_eq!(symbols.code_address("_main"), Some(0x0080));
+        assert_eq!(symbols.data_symbol(0x08), Some("_counter"));
+        assert_eq!(symbols.xdata_symbol(0x0000), Some("_buffer"));
+        // area lengths aren't symbols
+        assert_eq!(symbols.code_symbol(0x0099), None);
+        assert_eq!(symbols.code_address("l_CSEG"), None);
+    }
+}
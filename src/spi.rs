@@ -1,4 +1,4 @@
-use crate::mcs51::cpu::Address;
+use crate::mcs51::cpu::{Address, CpuError};
 use crate::mcs51::memory::Memory;
 
 use bitflags::bitflags;
@@ -83,7 +83,7 @@ impl SPI {
 }
 
 impl Memory for SPI {
-    fn read_memory(&mut self, address: Address) -> Result<u8, &'static str> {
+    fn read_memory(&mut self, address: Address) -> Result<u8, CpuError> {
         match address {
             Address::ExternalData(a) => match a & 1 {
                 // SPI Data Register
@@ -93,7 +93,7 @@ impl Memory for SPI {
 
                     // if the ttl hasn't reached, throw an error. undefined behavior on real hardware
                     if self.buffer_ttl > 1 {
-                        Err("buffer is not ready")
+                        Err(CpuError::Fault("buffer is not ready"))
                     } else {
                         Ok(self.buffer)
                     }
@@ -102,10 +102,10 @@ impl Memory for SPI {
                 1 => Ok(self.control.bits),
                 _ => panic!("impossible register"),
             },
-            _ => Err("unsupported address space"),
+            _ => Err(CpuError::Fault("unsupported address space")),
         }
     }
-    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), &'static str> {
+    fn write_memory(&mut self, address: Address, data: u8) -> Result<(), CpuError> {
         match address {
             Address::ExternalData(a) => match a & 1 {
                 // SPI Data Register
@@ -142,7 +142,7 @@ impl Memory for SPI {
                 }
                 _ => panic!("impossible register"),
             },
-            _ => Err("unsupported address space"),
+            _ => Err(CpuError::Fault("unsupported address space")),
         }
     }
 
@@ -0,0 +1,28 @@
+#![cfg(feature = "elf")]
+
+use p80c550_evn_emulator::board::Board;
+use p80c550_evn_emulator::mcs51::cpu::Address;
+use p80c550_evn_emulator::mcs51::memory::Memory;
+
+use std::path::Path;
+
+// tiny.elf is an ELF32 (little endian, EM_8051) executable with one PT_LOAD segment at 0x0000
+// holding "_start: ljmp _main; _main: sjmp _main" and both labels in its symbol table
+#[test]
+fn elf_loads_code_and_symbols() {
+    let mut board = Board::new();
+    board
+        .load_firmware(Path::new("tests/fixtures/tiny.elf"))
+        .unwrap();
+
+    let rom = board.cpu_mut().memory_mut().rom_mut();
+    let code: Vec<u8> = (0..5)
+        .map(|address| rom.read_memory(Address::Code(address)).unwrap())
+        .collect();
+    assert_eq!(code, [0x02, 0x00, 0x03, 0x80, 0xFE]);
+    assert_eq!(board.cpu().disassembler().code_label(0x0003), Some("_main"));
+    assert_eq!(
+        board.cpu().disassembler().code_label(0x0000),
+        Some("_start")
+    );
+}
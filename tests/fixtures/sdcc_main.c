@@ -0,0 +1,34 @@
+/*
+ * source of sdcc_main.ihx (sdcc -mmcs51 --model-small). the image follows sdcc's layout:
+ *
+ *   0000  ljmp __sdcc_gsinit_startup      reset vector (HOME)
+ *   0003  mov  sp,#__start__stack - 1     crt0 (GSINIT)
+ *   0006  lcall __sdcc_external_startup
+ *   0009  mov  a,dpl
+ *   000b  jz   __sdcc_init_data
+ *   000d  ljmp __sdcc_program_startup
+ *   0010  mov  _result,#0x00              __sdcc_init_data, clear the data area
+ *   0013  ljmp __sdcc_program_startup     GSFINAL
+ *   0016  ljmp _main                      __sdcc_program_startup
+ *   0019  mov  dpl,#0x00; ret             __sdcc_external_startup, 0 = initialize data
+ *   001d  _twice:
+ *         mov  r7,dpl; mov a,r7; add a,r7; mov dpl,a; ret
+ *   0024  _main:
+ *         mov  dpl,#0x15; lcall _twice; mov _result,dpl; mov _P1,_result; sjmp .
+ */
+#include <8051.h>
+
+__data unsigned char result;
+
+unsigned char twice(unsigned char x)
+{
+    return x + x;
+}
+
+void main(void)
+{
+    result = twice(21);
+    P1 = result;
+    while (1)
+        ;
+}
@@ -0,0 +1,32 @@
+use p80c550_evn_emulator::board::Board;
+use p80c550_evn_emulator::mcs51::cpu::Address;
+use p80c550_evn_emulator::mcs51::memory::Memory;
+
+use std::path::Path;
+
+// sdcc_main.ihx is the small model startup and main of sdcc_main.c, see there for the listing
+#[test]
+fn sdcc_main_calls_and_returns() {
+    let mut board = Board::new();
+    board
+        .load_firmware(Path::new("tests/fixtures/sdcc_main.ihx"))
+        .unwrap();
+
+    // run until main parks in its while (1)
+    let mut steps = 0;
+    while board.cpu().state().program_counter != 0x0030 {
+        board.step().unwrap();
+        steps += 1;
+        assert!(steps < 100, "main never reached its loop");
+    }
+
+    let state = board.cpu().state();
+    // the stack is back where crt0 put it after the call to twice returned
+    assert_eq!(state.stack_pointer, 0x08);
+    let soc = board.cpu_mut().memory_mut();
+    assert_eq!(soc.read_memory(Address::InternalData(0x08)), Ok(42));
+    assert_eq!(
+        soc.read_memory(Address::SpecialFunctionRegister(0x90)),
+        Ok(42)
+    );
+}